@@ -0,0 +1,43 @@
+use std::fmt;
+
+/// Errors returned by the fallible `Graph::try_*` methods.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GraphError {
+    /// A node id that is not in the graph.
+    MissingNode(usize),
+    /// The graph has no nodes to run a backward pass over.
+    EmptyGraph,
+    /// An op over a slice of nodes, such as `mean` or `log_softmax`, was given
+    /// none.
+    EmptyInput,
+    /// The node lists itself or a later node among its inputs.
+    Cycle(usize),
+    /// The inputs of this node were freed by a backward pass that did not
+    /// retain the graph.
+    Freed(usize),
+    /// The node is a custom op, which has no symbolic gradient.
+    Unsupported(usize),
+    /// An op such as `ln` needs this node's value to be positive.
+    NonPositive(usize),
+    /// An op such as `sqrt` needs this node's value to be non-negative.
+    Negative(usize),
+}
+
+impl fmt::Display for GraphError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GraphError::MissingNode(id) => write!(f, "node {} is not in the graph", id),
+            GraphError::EmptyGraph => write!(f, "the graph is empty"),
+            GraphError::EmptyInput => write!(f, "the op needs at least one input"),
+            GraphError::Cycle(id) => write!(f, "node {} is part of a cycle", id),
+            GraphError::Freed(id) => {
+                write!(f, "node {} was freed by an earlier backward pass", id)
+            }
+            GraphError::Unsupported(id) => write!(f, "node {} has no symbolic gradient", id),
+            GraphError::NonPositive(id) => write!(f, "node {} is not positive", id),
+            GraphError::Negative(id) => write!(f, "node {} is negative", id),
+        }
+    }
+}
+
+impl std::error::Error for GraphError {}
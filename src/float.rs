@@ -0,0 +1,57 @@
+use num_traits::float::TotalOrder;
+use num_traits::NumAssign;
+use std::fmt;
+use std::iter::Sum;
+
+/// Scalar type a graph is built over. Implemented for `f32` and `f64`; `Node`,
+/// `Graph` and `Value` default to `f64`.
+pub trait Float:
+    num_traits::Float
+    + NumAssign
+    + TotalOrder
+    + fmt::Debug
+    + fmt::Display
+    + Sum
+    + for<'a> Sum<&'a Self>
+    + Send
+    + Sync
+    + 'static
+{
+    /// Converts an `f64` constant, rounding if `Self` is narrower.
+    fn from_f64(x: f64) -> Self;
+
+    /// The bit pattern of `self`, widened to 64 bits, so the parallel backward
+    /// pass can accumulate gradients in an `AtomicU64`.
+    fn to_bits64(self) -> u64;
+
+    /// Inverse of `to_bits64`.
+    fn from_bits64(bits: u64) -> Self;
+}
+
+impl Float for f32 {
+    fn from_f64(x: f64) -> Self {
+        x as f32
+    }
+
+    fn to_bits64(self) -> u64 {
+        u64::from(self.to_bits())
+    }
+
+    fn from_bits64(bits: u64) -> Self {
+        f32::from_bits(bits as u32)
+    }
+}
+
+impl Float for f64 {
+    fn from_f64(x: f64) -> Self {
+        x
+    }
+
+    fn to_bits64(self) -> u64 {
+        self.to_bits()
+    }
+
+    fn from_bits64(bits: u64) -> Self {
+        f64::from_bits(bits)
+    }
+}
@@ -0,0 +1,696 @@
+use crate::error::GraphError;
+use crate::float::Float;
+use crate::node::hooks::Hooks;
+use crate::node::{self, CustomOp, Map, Node, Overrides, Ste};
+use crate::rng::RngBackend;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+/// Owns the node arena and the parent map of one computation, so ops, forward
+/// recomputation and the backward pass go through a single object.
+#[derive(Debug)]
+pub struct Graph<T = f64> {
+    map: Map,
+    nodes: Vec<Node<T>>,
+    hooks: Hooks<T>,
+    /// Nodes whose inputs `backward_retain` has dropped from `map`.
+    freed: HashSet<usize>,
+    /// The length of the graph when the outermost running `no_grad` was
+    /// entered. The map it set aside refers to the nodes before that, so they
+    /// may not be renumbered or dropped until it returns.
+    no_grad_len: Option<usize>,
+}
+
+// Restores the map `Graph::no_grad` set aside and detaches the nodes built
+// since, when the closure returns or unwinds.
+struct NoGrad<'a, T: Float> {
+    graph: &'a mut Graph<T>,
+    map: Map,
+    len: usize,
+    outer_len: Option<usize>,
+}
+
+// `Graph::new` is only defined for `f64`, so `Graph::new()` needs no type
+// annotation; other float types start from `Graph::<f32>::default()`.
+impl<T: Float> Default for Graph<T> {
+    fn default() -> Self {
+        Graph {
+            map: HashMap::new(),
+            nodes: Vec::new(),
+            hooks: Hooks::new(),
+            freed: HashSet::new(),
+            no_grad_len: None,
+        }
+    }
+}
+
+impl<T: Float> Drop for NoGrad<'_, T> {
+    fn drop(&mut self) {
+        let len = self.len.min(self.graph.nodes.len());
+        self.graph.map = std::mem::take(&mut self.map);
+        self.graph.no_grad_len = self.outer_len;
+        node::detach(&mut self.graph.nodes[len..]);
+    }
+}
+
+impl<T: Float> Graph<T> {
+    pub fn nodes(&self) -> &[Node<T>] {
+        &self.nodes
+    }
+
+    pub fn map(&self) -> &Map {
+        &self.map
+    }
+
+    /// Both collections, for calling the free functions in `node` directly.
+    pub fn parts_mut(&mut self) -> (&mut Map, &mut Vec<Node<T>>) {
+        (&mut self.map, &mut self.nodes)
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn value(&self, id: usize) -> T {
+        self.nodes[id].value
+    }
+
+    pub fn gradient(&self, id: usize) -> T {
+        self.nodes[id].gradient
+    }
+
+    pub fn leaf(&mut self, value: T) -> usize {
+        node::new_node(&mut self.nodes, value)
+    }
+
+    /// See `node::constant`.
+    pub fn constant(&mut self, value: T) -> usize {
+        node::constant(&mut self.nodes, value)
+    }
+
+    pub fn add(&mut self, a: usize, b: usize) -> usize {
+        node::add(&mut self.map, &mut self.nodes, a, b).0
+    }
+
+    pub fn sub(&mut self, a: usize, b: usize) -> usize {
+        node::sub(&mut self.map, &mut self.nodes, a, b).0
+    }
+
+    pub fn add_scalar(&mut self, x: usize, scalar: T) -> usize {
+        node::add_scalar(&mut self.map, &mut self.nodes, x, scalar).0
+    }
+
+    pub fn mul_scalar(&mut self, x: usize, scalar: T) -> usize {
+        node::mul_scalar(&mut self.map, &mut self.nodes, x, scalar).0
+    }
+
+    pub fn pow_scalar(&mut self, x: usize, exponent: T) -> usize {
+        node::pow_scalar(&mut self.map, &mut self.nodes, x, exponent).0
+    }
+
+    pub fn neg(&mut self, x: usize) -> usize {
+        node::neg(&mut self.map, &mut self.nodes, x).0
+    }
+
+    pub fn mul(&mut self, a: usize, b: usize) -> usize {
+        node::mul(&mut self.map, &mut self.nodes, a, b).0
+    }
+
+    pub fn div(&mut self, a: usize, b: usize) -> usize {
+        node::div(&mut self.map, &mut self.nodes, a, b).0
+    }
+
+    pub fn pow(&mut self, a: usize, b: usize) -> usize {
+        node::pow(&mut self.map, &mut self.nodes, a, b).0
+    }
+
+    pub fn relu(&mut self, x: usize) -> usize {
+        node::relu(&mut self.map, &mut self.nodes, x).0
+    }
+
+    pub fn tanh(&mut self, x: usize) -> usize {
+        node::tanh(&mut self.map, &mut self.nodes, x).0
+    }
+
+    pub fn sigmoid(&mut self, x: usize) -> usize {
+        node::sigmoid(&mut self.map, &mut self.nodes, x).0
+    }
+
+    pub fn leaky_relu(&mut self, x: usize, alpha: T) -> usize {
+        node::leaky_relu(&mut self.map, &mut self.nodes, x, alpha).0
+    }
+
+    pub fn gelu(&mut self, x: usize) -> usize {
+        node::gelu(&mut self.map, &mut self.nodes, x).0
+    }
+
+    pub fn elu(&mut self, x: usize, alpha: T) -> usize {
+        node::elu(&mut self.map, &mut self.nodes, x, alpha).0
+    }
+
+    pub fn mish(&mut self, x: usize) -> usize {
+        node::mish(&mut self.map, &mut self.nodes, x).0
+    }
+
+    pub fn prelu(&mut self, x: usize, slope: usize) -> usize {
+        node::prelu(&mut self.map, &mut self.nodes, x, slope).0
+    }
+
+    pub fn sqrt(&mut self, x: usize) -> usize {
+        node::sqrt(&mut self.map, &mut self.nodes, x).0
+    }
+
+    pub fn abs(&mut self, x: usize) -> usize {
+        node::abs(&mut self.map, &mut self.nodes, x).0
+    }
+
+    pub fn max(&mut self, a: usize, b: usize) -> usize {
+        node::max(&mut self.map, &mut self.nodes, a, b).0
+    }
+
+    pub fn min(&mut self, a: usize, b: usize) -> usize {
+        node::min(&mut self.map, &mut self.nodes, a, b).0
+    }
+
+    pub fn clamp(&mut self, x: usize, lo: T, hi: T) -> usize {
+        node::clamp(&mut self.map, &mut self.nodes, x, lo, hi).0
+    }
+
+    pub fn sin(&mut self, x: usize) -> usize {
+        node::sin(&mut self.map, &mut self.nodes, x).0
+    }
+
+    pub fn cos(&mut self, x: usize) -> usize {
+        node::cos(&mut self.map, &mut self.nodes, x).0
+    }
+
+    pub fn exp(&mut self, x: usize) -> usize {
+        node::exp(&mut self.map, &mut self.nodes, x).0
+    }
+
+    pub fn ln(&mut self, x: usize) -> usize {
+        node::ln(&mut self.map, &mut self.nodes, x).0
+    }
+
+    pub fn ln_eps(&mut self, x: usize, eps: T) -> usize {
+        node::ln_eps(&mut self.map, &mut self.nodes, x, eps).0
+    }
+
+    pub fn gt(&mut self, a: usize, b: usize) -> usize {
+        node::gt(&mut self.map, &mut self.nodes, a, b).0
+    }
+
+    pub fn lt(&mut self, a: usize, b: usize) -> usize {
+        node::lt(&mut self.map, &mut self.nodes, a, b).0
+    }
+
+    pub fn ge(&mut self, a: usize, b: usize) -> usize {
+        node::ge(&mut self.map, &mut self.nodes, a, b).0
+    }
+
+    pub fn le(&mut self, a: usize, b: usize) -> usize {
+        node::le(&mut self.map, &mut self.nodes, a, b).0
+    }
+
+    pub fn eq(&mut self, a: usize, b: usize) -> usize {
+        node::eq(&mut self.map, &mut self.nodes, a, b).0
+    }
+
+    pub fn sign(&mut self, x: usize, ste: Ste) -> usize {
+        node::sign(&mut self.map, &mut self.nodes, x, ste).0
+    }
+
+    pub fn round(&mut self, x: usize, ste: Ste) -> usize {
+        node::round(&mut self.map, &mut self.nodes, x, ste).0
+    }
+
+    pub fn hard_threshold(&mut self, x: usize, threshold: T, ste: Ste) -> usize {
+        node::hard_threshold(&mut self.map, &mut self.nodes, x, threshold, ste).0
+    }
+
+    pub fn squared_error(&mut self, x: usize, target: T) -> usize {
+        node::squared_error(&mut self.map, &mut self.nodes, x, target).0
+    }
+
+    pub fn sample_normal(&mut self, mu: usize, log_var: usize, rng: &mut impl RngBackend) -> usize {
+        node::sample_normal(&mut self.map, &mut self.nodes, mu, log_var, rng).0
+    }
+
+    pub fn gaussian_kl(&mut self, mu: &[usize], log_var: &[usize]) -> usize {
+        node::gaussian_kl(&mut self.map, &mut self.nodes, mu, log_var)
+    }
+
+    pub fn top_k(&mut self, xs: &[usize], k: usize) -> (Vec<usize>, Vec<usize>) {
+        node::top_k(&mut self.map, &mut self.nodes, xs, k)
+    }
+
+    pub fn sum(&mut self, xs: &[usize]) -> usize {
+        node::sum(&mut self.map, &mut self.nodes, xs)
+    }
+
+    pub fn dot(&mut self, xs: &[usize], ws: &[usize]) -> usize {
+        node::dot(&mut self.map, &mut self.nodes, xs, ws)
+    }
+
+    pub fn cosine_similarity(&mut self, xs: &[usize], ys: &[usize]) -> usize {
+        node::cosine_similarity(&mut self.map, &mut self.nodes, xs, ys)
+    }
+
+    pub fn mean(&mut self, xs: &[usize]) -> Result<usize, GraphError> {
+        node::mean(&mut self.map, &mut self.nodes, xs)
+    }
+
+    pub fn prod(&mut self, xs: &[usize]) -> usize {
+        node::prod(&mut self.map, &mut self.nodes, xs)
+    }
+
+    pub fn cumsum(&mut self, xs: &[usize]) -> Vec<usize> {
+        node::cumsum(&mut self.map, &mut self.nodes, xs)
+    }
+
+    pub fn log_softmax(&mut self, xs: &[usize]) -> Result<Vec<usize>, GraphError> {
+        node::log_softmax(&mut self.map, &mut self.nodes, xs)
+    }
+
+    pub fn custom(&mut self, op: Arc<dyn CustomOp<T>>, inputs: &[usize]) -> usize {
+        node::custom(&mut self.map, &mut self.nodes, op, inputs).0
+    }
+
+    pub fn logsumexp(&mut self, xs: &[usize]) -> usize {
+        node::logsumexp(&mut self.map, &mut self.nodes, xs)
+    }
+
+    pub fn softmax(&mut self, xs: &[usize]) -> Result<Vec<usize>, GraphError> {
+        node::softmax(&mut self.map, &mut self.nodes, xs)
+    }
+
+    /// See `node::custom_unary`.
+    pub fn custom_unary<F, B>(&mut self, x: usize, forward: F, backward: B) -> usize
+    where
+        F: Fn(T) -> T + Send + Sync + 'static,
+        B: Fn(T, T) -> T + Send + Sync + 'static,
+    {
+        node::custom_unary(&mut self.map, &mut self.nodes, x, forward, backward).0
+    }
+
+    pub fn select(&mut self, condition: usize, a: usize, b: usize) -> usize {
+        node::select(&mut self.map, &mut self.nodes, condition, a, b).0
+    }
+
+    /// See `node::cond`.
+    pub fn cond<Then, Else>(
+        &mut self,
+        predicate: usize,
+        then_builder: Then,
+        else_builder: Else,
+    ) -> usize
+    where
+        Then: FnOnce(&mut Self) -> usize,
+        Else: FnOnce(&mut Self) -> usize,
+    {
+        let then_branch = then_builder(self);
+        let else_branch = else_builder(self);
+        self.select(predicate, then_branch, else_branch)
+    }
+
+    /// Runs `f` without recording the inputs of the nodes it builds, for
+    /// evaluation-only passes. Those nodes hold their values but are leaves:
+    /// `backward` and `forward` do not look past them. The recorded graph is
+    /// put back even if `f` panics.
+    ///
+    /// `prune` and `optimize` panic inside `f`, as does `reset_to` below the
+    /// length the graph had on entry: the recorded graph could not be put
+    /// back over renumbered nodes.
+    pub fn no_grad<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        let len = self.nodes.len();
+        let outer_len = self.no_grad_len;
+        self.no_grad_len = Some(outer_len.unwrap_or(len));
+
+        let guard = NoGrad {
+            map: std::mem::take(&mut self.map),
+            len,
+            outer_len,
+            graph: self,
+        };
+        f(guard.graph)
+    }
+
+    /// See `node::scan`.
+    pub fn scan<F>(&mut self, n_steps: usize, state_init: Vec<usize>, step_fn: F) -> Vec<Vec<usize>>
+    where
+        F: FnMut(&mut Self, usize, &[usize]) -> Vec<usize>,
+    {
+        node::unroll(self, n_steps, state_init, step_fn)
+    }
+
+    pub fn set_value(&mut self, id: usize, value: T) {
+        node::set_value(&mut self.nodes, id, value);
+    }
+
+    pub fn set_requires_grad(&mut self, id: usize, requires_grad: bool) {
+        node::set_requires_grad(&mut self.nodes, id, requires_grad);
+    }
+
+    /// Excludes each of `ids` from the backward pass and from `sgd_step`.
+    pub fn freeze(&mut self, ids: &[usize]) {
+        for &id in ids {
+            self.set_requires_grad(id, false);
+        }
+    }
+
+    pub fn unfreeze(&mut self, ids: &[usize]) {
+        for &id in ids {
+            self.set_requires_grad(id, true);
+        }
+    }
+
+    pub fn sgd_step(&mut self, parameters: &[usize], learning_rate: T) {
+        node::sgd_step(&mut self.nodes, parameters, learning_rate);
+    }
+
+    /// Rolls the graph back to its first `len` nodes, usually a `len()` taken
+    /// once the parameters are built, so each training step can reuse the
+    /// same graph instead of growing it. Hooks on dropped nodes go too.
+    pub fn reset_to(&mut self, len: usize) {
+        if let Some(floor) = self.no_grad_len {
+            assert!(
+                len >= floor,
+                "reset_to({}) inside no_grad, which was entered at {} nodes",
+                len,
+                floor
+            );
+        }
+        node::truncate(&mut self.map, &mut self.nodes, len);
+        self.hooks.truncate(len);
+        self.freed.retain(|&id| id < len);
+    }
+
+    /// See `node::prune`. Hooks move with their nodes. Panics inside
+    /// `no_grad`.
+    pub fn prune(&mut self, outputs: &[usize]) -> Vec<Option<usize>> {
+        self.assert_not_in_no_grad("prune");
+        let ids = node::prune(&mut self.map, &mut self.nodes, outputs);
+        self.hooks.remap(&ids);
+        self.freed = self.freed.iter().filter_map(|&id| ids[id]).collect();
+        ids
+    }
+
+    fn assert_not_in_no_grad(&self, operation: &str) {
+        assert!(
+            self.no_grad_len.is_none(),
+            "{} would renumber the nodes no_grad has to restore",
+            operation
+        );
+    }
+
+    /// See `node::extract`. The new graph has no hooks.
+    pub fn extract(&self, inputs: &[usize], outputs: &[usize]) -> (Self, Vec<Option<usize>>) {
+        let (map, nodes, ids) = node::extract(&self.map, &self.nodes, inputs, outputs);
+        let graph = Graph {
+            map,
+            nodes,
+            ..Graph::default()
+        };
+        (graph, ids)
+    }
+
+    /// See `node::optimize::optimize`. Hooks move with their nodes; those on
+    /// merged nodes are dropped. Panics inside `no_grad`.
+    pub fn optimize(&mut self, outputs: &[usize]) -> Vec<Option<usize>> {
+        self.assert_not_in_no_grad("optimize");
+        let ids = node::optimize::optimize(&mut self.map, &mut self.nodes, outputs);
+        self.hooks.remap(&ids);
+        self.freed = self.freed.iter().filter_map(|&id| ids[id]).collect();
+        ids
+    }
+
+    pub fn zero_grad(&mut self) {
+        node::zero_grad(&mut self.nodes);
+    }
+
+    pub fn zero_grad_of(&mut self, ids: &[usize]) {
+        node::zero_grad_of(&mut self.nodes, ids);
+    }
+
+    /// Attaches a hook that runs whenever `forward` or `eval` recomputes
+    /// `id`; see `node::hooks::ForwardHook`.
+    pub fn on_forward(&mut self, id: usize, hook: impl Fn(usize, T) + 'static) {
+        self.hooks.on_forward(id, hook);
+    }
+
+    /// Attaches a hook that sees, and may rewrite, every gradient contribution
+    /// to `id`; see `node::hooks::BackwardHook`.
+    pub fn on_backward(&mut self, id: usize, hook: impl Fn(usize, T) -> T + 'static) {
+        self.hooks.on_backward(id, hook);
+    }
+
+    pub fn hooks_mut(&mut self) -> &mut Hooks<T> {
+        &mut self.hooks
+    }
+
+    pub fn forward(&mut self) {
+        node::forward_with_hooks(&self.map, &mut self.nodes, &self.hooks);
+    }
+
+    pub fn eval(&mut self, id: usize) -> T {
+        node::eval_with_hooks(&self.map, &mut self.nodes, id, &self.hooks)
+    }
+
+    /// Seeds `output` with a gradient of 1.0 and backpropagates into the nodes
+    /// it was computed from. Panics with `GraphError::Freed` if the pass would
+    /// go through inputs freed by `backward_retain`, as do the other backward
+    /// passes.
+    pub fn backward(&mut self, output: usize) {
+        self.backward_with_overrides(output, &Overrides::new());
+    }
+
+    pub fn backward_with_overrides(&mut self, output: usize, overrides: &Overrides<T>) {
+        self.assert_not_freed(&[output]);
+        node::backpropagate(
+            &self.map,
+            &mut self.nodes,
+            &[(output, T::one())],
+            overrides,
+            &self.hooks,
+        );
+    }
+
+    /// See `node::jacobian`. Backward hooks are not run.
+    pub fn jacobian(&mut self, outputs: &[usize], inputs: &[usize]) -> Vec<Vec<T>> {
+        node::jacobian(&mut self.map, &mut self.nodes, outputs, inputs)
+    }
+
+    /// See `node::symbolic::hvp`. Backward hooks are not run.
+    pub fn hvp(&mut self, output: usize, params: &[usize], v: &[T]) -> Result<Vec<T>, GraphError> {
+        node::symbolic::hvp(&mut self.map, &mut self.nodes, output, params, v)
+    }
+
+    /// See `node::gradcheck::gradcheck`. Backward hooks are not run.
+    pub fn gradcheck(
+        &mut self,
+        output: usize,
+        params: &[usize],
+        eps: T,
+        tol: T,
+    ) -> Vec<node::gradcheck::Mismatch<T>> {
+        node::gradcheck::gradcheck(&mut self.map, &mut self.nodes, output, params, eps, tol)
+    }
+
+    /// See `node::dual::jvp`.
+    pub fn jvp(&self, seeds: &[(usize, T)], outputs: &[usize]) -> Vec<node::dual::Dual<T>> {
+        node::dual::jvp(&self.map, &self.nodes, seeds, outputs)
+    }
+
+    /// See `node::symbolic::grad`.
+    pub fn grad(&mut self, output: usize, wrt: &[usize]) -> Result<Vec<usize>, GraphError> {
+        node::symbolic::grad(&mut self.map, &mut self.nodes, output, wrt)
+    }
+
+    /// See `node::backwards_multi`; backward hooks are run.
+    pub fn backward_multi(&mut self, seeds: &[(usize, T)]) {
+        let outputs: Vec<usize> = seeds.iter().map(|&(id, _)| id).collect();
+        self.assert_not_freed(&outputs);
+        node::backpropagate(
+            &self.map,
+            &mut self.nodes,
+            seeds,
+            &Overrides::new(),
+            &self.hooks,
+        );
+    }
+
+    /// `backward`, then, unless `retain_graph` is set, frees the recorded
+    /// inputs of every node the pass went through. Freed nodes keep their
+    /// values and gradients but `forward` no longer recomputes them, and a
+    /// later backward pass through any of them returns `GraphError::Freed`.
+    pub fn backward_retain(&mut self, output: usize, retain_graph: bool) -> Result<(), GraphError> {
+        self.check(&[output])?;
+        let visited = self.check_not_freed(&[output])?;
+
+        node::backpropagate(
+            &self.map,
+            &mut self.nodes,
+            &[(output, T::one())],
+            &Overrides::new(),
+            &self.hooks,
+        );
+
+        if !retain_graph {
+            for id in visited {
+                if self.map.remove(&id).is_some() {
+                    self.freed.insert(id);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// `backward` using `node::parallel::backwards_parallel`. Backward hooks
+    /// are not run.
+    pub fn backward_parallel(&mut self, output: usize, threads: usize) {
+        self.assert_not_freed(&[output]);
+        node::parallel::backwards_parallel(&self.map, &mut self.nodes, output, threads);
+    }
+
+    /// Returns every node a backward pass from `outputs` goes through, or
+    /// `GraphError::Freed` for the first of them whose inputs were freed.
+    fn check_not_freed(&self, outputs: &[usize]) -> Result<HashSet<usize>, GraphError> {
+        let mut visited = HashSet::new();
+        let mut stack = outputs.to_vec();
+        while let Some(id) = stack.pop() {
+            if self.freed.contains(&id) {
+                return Err(GraphError::Freed(id));
+            }
+            if visited.insert(id) {
+                if let Some(children) = self.map.get(&id) {
+                    stack.extend(children.iter().copied());
+                }
+            }
+        }
+        Ok(visited)
+    }
+
+    fn assert_not_freed(&self, outputs: &[usize]) {
+        if self.freed.is_empty() {
+            return;
+        }
+        if let Err(error) = self.check_not_freed(outputs) {
+            panic!("{}", error);
+        }
+    }
+
+    /// Checks that every id in `ids` names a node of this graph.
+    fn check(&self, ids: &[usize]) -> Result<(), GraphError> {
+        match ids.iter().find(|&&id| id >= self.nodes.len()) {
+            Some(&id) => Err(GraphError::MissingNode(id)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn try_value(&self, id: usize) -> Result<T, GraphError> {
+        self.check(&[id])?;
+        Ok(self.value(id))
+    }
+
+    pub fn try_gradient(&self, id: usize) -> Result<T, GraphError> {
+        self.check(&[id])?;
+        Ok(self.gradient(id))
+    }
+
+    pub fn try_add(&mut self, a: usize, b: usize) -> Result<usize, GraphError> {
+        self.check(&[a, b])?;
+        Ok(self.add(a, b))
+    }
+
+    pub fn try_sub(&mut self, a: usize, b: usize) -> Result<usize, GraphError> {
+        self.check(&[a, b])?;
+        Ok(self.sub(a, b))
+    }
+
+    pub fn try_mul(&mut self, a: usize, b: usize) -> Result<usize, GraphError> {
+        self.check(&[a, b])?;
+        Ok(self.mul(a, b))
+    }
+
+    pub fn try_div(&mut self, a: usize, b: usize) -> Result<usize, GraphError> {
+        self.check(&[a, b])?;
+        Ok(self.div(a, b))
+    }
+
+    pub fn try_pow(&mut self, a: usize, b: usize) -> Result<usize, GraphError> {
+        self.check(&[a, b])?;
+        Ok(self.pow(a, b))
+    }
+
+    pub fn try_relu(&mut self, x: usize) -> Result<usize, GraphError> {
+        self.check(&[x])?;
+        Ok(self.relu(x))
+    }
+
+    pub fn try_exp(&mut self, x: usize) -> Result<usize, GraphError> {
+        self.check(&[x])?;
+        Ok(self.exp(x))
+    }
+
+    /// `ln`, but refusing inputs that are not positive.
+    pub fn try_ln(&mut self, x: usize) -> Result<usize, GraphError> {
+        if self.try_value(x)? <= T::zero() {
+            return Err(GraphError::NonPositive(x));
+        }
+        Ok(self.ln(x))
+    }
+
+    /// `sqrt`, but refusing negative inputs.
+    pub fn try_sqrt(&mut self, x: usize) -> Result<usize, GraphError> {
+        if self.try_value(x)? < T::zero() {
+            return Err(GraphError::Negative(x));
+        }
+        Ok(self.sqrt(x))
+    }
+
+    /// `backward`, but reporting an empty graph, a bad `output`, a map entry
+    /// that points at its own node or a later one, or freed inputs instead of
+    /// panicking.
+    pub fn try_backward(&mut self, output: usize) -> Result<(), GraphError> {
+        if self.nodes.is_empty() {
+            return Err(GraphError::EmptyGraph);
+        }
+        self.check(&[output])?;
+
+        for (&id, children) in self.map.iter() {
+            self.check(&[id])?;
+            self.check(children)?;
+            if children.iter().any(|&child| child >= id) {
+                return Err(GraphError::Cycle(id));
+            }
+        }
+        self.check_not_freed(&[output])?;
+
+        self.backward(output);
+        Ok(())
+    }
+
+    pub fn stats(&self) -> node::stats::GraphStats {
+        node::stats::stats(&self.map, &self.nodes)
+    }
+}
+
+// Checkpointed segments are rerun on scratch `f64` graphs, so `checkpoint` is
+// only available on those.
+impl Graph {
+    pub fn new() -> Self {
+        Graph::default()
+    }
+
+    /// See `node::checkpoint::checkpoint`.
+    pub fn checkpoint<F>(&mut self, inputs: &[usize], segment: F) -> Vec<usize>
+    where
+        F: Fn(&mut Graph, &[usize]) -> Vec<usize> + Send + Sync + 'static,
+    {
+        node::checkpoint::checkpoint(&mut self.map, &mut self.nodes, inputs, Arc::new(segment))
+    }
+}
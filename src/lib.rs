@@ -0,0 +1,2672 @@
+//! A tiny autograd engine based on Andrej Karpathy's micrograd.
+//!
+//! Nodes live in a `Vec<Node>` arena and the inputs of every computed node
+//! are recorded in a `Map`; ops append to both and `backwards` walks them to
+//! fill in gradients. `Graph` owns the two together.
+
+pub mod error;
+pub mod float;
+pub mod graph;
+pub mod nn;
+pub mod node;
+pub mod npy;
+pub mod repl;
+pub mod rng;
+pub mod vae;
+pub mod value;
+
+pub use error::GraphError;
+pub use float::Float;
+pub use graph::Graph;
+pub use node::scalar::Scalar;
+pub use node::{add, backwards, backwards_with_overrides, eval, forward, mul, new_node, set_value};
+pub use node::{Activation, Layer, Map, Neuron, Node};
+pub use rng::RngBackend;
+pub use value::Value;
+
+mod test {
+    #[test]
+    fn basics() {
+        // TODO
+    }
+
+    #[test]
+    fn comparison_masks_stop_gradient() {
+        use crate::node;
+        use std::collections::HashMap;
+
+        let mut map: node::Map = HashMap::new();
+        let mut nodes: Vec<node::Node> = Vec::new();
+
+        let x = node::new_node(&mut nodes, 2.0);
+        let y = node::new_node(&mut nodes, 1.0);
+
+        let (mask, value) = node::gt(&mut map, &mut nodes, x, y);
+        assert_eq!(value, 1.0);
+        assert_eq!(node::lt(&mut map, &mut nodes, x, y).1, 0.0);
+        assert_eq!(node::ge(&mut map, &mut nodes, x, x).1, 1.0);
+        assert_eq!(node::le(&mut map, &mut nodes, y, x).1, 1.0);
+        assert_eq!(node::eq(&mut map, &mut nodes, x, y).1, 0.0);
+
+        let (out, _) = node::mul(&mut map, &mut nodes, x, mask);
+        node::backwards(&mut map, &mut nodes, out);
+
+        assert_eq!(nodes[x].gradient, 1.0);
+        assert_eq!(nodes[y].gradient, 0.0);
+    }
+
+    #[test]
+    fn parses_npy_arrays() {
+        use crate::npy;
+
+        let mut header = "{'descr': '<f8', 'fortran_order': False, 'shape': (2, 3), }".to_string();
+        header.push('\n');
+
+        let mut bytes = b"\x93NUMPY\x01\x00".to_vec();
+        bytes.extend((header.len() as u16).to_le_bytes());
+        bytes.extend(header.as_bytes());
+        for value in [1.0f64, 2.0, 3.0, 4.0, 5.0, 6.0] {
+            bytes.extend(value.to_le_bytes());
+        }
+
+        let array = npy::parse(&bytes).unwrap();
+        assert_eq!(array.shape, [2, 3]);
+        assert_eq!(array.data, [1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+
+        assert!(npy::parse(&bytes[..bytes.len() - 8]).is_err());
+        assert!(npy::parse(b"not numpy at all").is_err());
+    }
+
+    #[test]
+    fn independent_graphs() {
+        use crate::node;
+        use std::collections::HashMap;
+
+        let mut first_nodes: Vec<node::Node> = Vec::new();
+        let _teacher = node::Layer::new(&mut first_nodes, 3, 4);
+
+        let mut map: node::Map = HashMap::new();
+        let mut nodes: Vec<node::Node> = Vec::new();
+
+        let x = node::new_node(&mut nodes, 3.0);
+        let y = node::new_node(&mut nodes, 4.0);
+        assert_eq!((x, y), (0, 1));
+
+        let (out, _) = node::mul(&mut map, &mut nodes, x, y);
+        node::backwards(&mut map, &mut nodes, out);
+
+        assert_eq!(nodes[x].gradient, 4.0);
+        assert_eq!(nodes[y].gradient, 3.0);
+    }
+
+    #[test]
+    fn repeated_and_unordered_children() {
+        use crate::node;
+        use std::collections::HashMap;
+
+        let mut map: node::Map = HashMap::new();
+        let mut nodes: Vec<node::Node> = Vec::new();
+
+        let x = node::new_node(&mut nodes, 3.0);
+        let (square, _) = node::mul(&mut map, &mut nodes, x, x);
+        let bias = node::new_node(&mut nodes, 1.0);
+        // The first child has a larger id than the second.
+        let (out, value) = node::add(&mut map, &mut nodes, bias, square);
+        assert_eq!(value, 10.0);
+
+        node::backwards(&mut map, &mut nodes, out);
+
+        assert_eq!(nodes[x].gradient, 6.0);
+        assert_eq!(nodes[bias].gradient, 1.0);
+    }
+
+    #[test]
+    fn graph_owns_nodes_and_map() {
+        use crate::Graph;
+
+        let mut graph = Graph::new();
+
+        let x = graph.leaf(2.0);
+        let w = graph.leaf(-3.0);
+        let b = graph.leaf(10.0);
+        let xw = graph.mul(x, w);
+        let sum = graph.add(xw, b);
+        let y = graph.relu(sum);
+        assert_eq!(graph.value(y), 4.0);
+
+        graph.backward(y);
+
+        assert_eq!(graph.gradient(x), -3.0);
+        assert_eq!(graph.gradient(w), 2.0);
+        assert_eq!(graph.gradient(b), 1.0);
+    }
+
+    #[test]
+    fn value_operators() {
+        use crate::{Graph, Value};
+        use std::cell::RefCell;
+
+        let graph = RefCell::new(Graph::new());
+
+        let x = Value::new(&graph, 2.0);
+        let w = Value::new(&graph, 3.0);
+        let b = Value::new(&graph, 1.0);
+        let d = Value::new(&graph, 4.0);
+
+        let y = ((x * w + b) / d - b).relu();
+        assert_eq!(y.data(), 0.75);
+
+        y.backward();
+
+        assert_eq!(x.grad(), 0.75);
+        assert_eq!(w.grad(), 0.5);
+        assert_eq!(d.grad(), -7.0 / 16.0);
+    }
+
+    #[test]
+    fn modules_compose() {
+        use crate::nn::{self, Module};
+        use crate::{Graph, Layer};
+
+        let mut graph = Graph::new();
+
+        let (_, nodes) = graph.parts_mut();
+        let hidden = Layer::new(nodes, 2, 3);
+        let output = Layer::new(nodes, 3, 1);
+
+        let blocks: Vec<Box<dyn Module>> =
+            vec![Box::new(hidden), Box::new(nn::Relu), Box::new(output)];
+
+        let parameters: Vec<usize> = blocks.iter().flat_map(|m| m.parameters()).collect();
+        assert_eq!(parameters.len(), 3 * 3 + 4);
+
+        let x = vec![graph.leaf(1.0), graph.leaf(-1.0)];
+        let y = blocks.iter().fold(x, |h, m| m.forward(&mut graph, &h));
+
+        assert_eq!(y.len(), 1);
+        assert!(graph.value(y[0]) >= 0.0);
+    }
+
+    #[test]
+    fn fallible_graph_api() {
+        use crate::{Graph, GraphError};
+
+        let mut graph = Graph::new();
+        assert_eq!(graph.try_backward(0), Err(GraphError::EmptyGraph));
+
+        let x = graph.leaf(2.0);
+        assert_eq!(graph.try_add(x, 7), Err(GraphError::MissingNode(7)));
+        assert_eq!(graph.len(), 1);
+
+        let y = graph.try_mul(x, x).unwrap();
+        let z = graph.try_relu(y).unwrap();
+        assert_eq!(graph.try_backward(9), Err(GraphError::MissingNode(9)));
+
+        graph.try_backward(z).unwrap();
+        assert_eq!(graph.gradient(x), 4.0);
+    }
+
+    #[test]
+    fn f32_graphs() {
+        use crate::node::{self, Node};
+        use std::collections::HashMap;
+
+        let mut map: node::Map = HashMap::new();
+        let mut nodes: Vec<Node<f32>> = Vec::new();
+
+        let x = node::new_node(&mut nodes, 1.5f32);
+        let y = node::new_node(&mut nodes, -2.0f32);
+        let (product, _) = node::mul(&mut map, &mut nodes, x, y);
+        let (shifted, _) = node::add(&mut map, &mut nodes, product, x);
+        let (out, value) = node::exp(&mut map, &mut nodes, shifted);
+        assert_eq!(value, (-1.5f32).exp());
+
+        node::backwards(&mut map, &mut nodes, out);
+
+        assert_eq!(nodes[x].gradient, -value);
+        assert_eq!(nodes[y].gradient, value * 1.5);
+    }
+
+    #[test]
+    fn f32_graph_and_values() {
+        use crate::{Graph, Value};
+        use std::cell::RefCell;
+
+        let mut graph: Graph<f32> = Graph::default();
+        let x = graph.leaf(1.5);
+        let y = graph.leaf(-2.0);
+        let product = graph.mul(x, y);
+        let shifted = graph.add(product, x);
+        let out = graph.exp(shifted);
+        let value = graph.value(out);
+        assert_eq!(value, (-1.5f32).exp());
+
+        graph.backward(out);
+        assert_eq!(graph.gradient(x), -value);
+        assert_eq!(graph.gradient(y), value * 1.5);
+
+        graph.zero_grad();
+        graph.backward_parallel(out, 2);
+        assert_eq!(graph.gradient(x), -value);
+        assert_eq!(graph.gradient(y), value * 1.5);
+
+        let graph = RefCell::new(Graph::<f32>::default());
+        let x = Value::new(&graph, 3.0f32);
+        let y = (x * x + 1.0).tanh();
+        y.backward();
+        assert_eq!(y.data(), 10.0f32.tanh());
+        assert_eq!(x.grad(), (1.0 - y.data() * y.data()) * 6.0);
+    }
+
+    #[test]
+    fn seeded_initialization_is_reproducible() {
+        use crate::node::{Layer, Node};
+
+        let mut first: Vec<Node> = Vec::new();
+        let mut second: Vec<Node> = Vec::new();
+
+        let a = Layer::new_seeded(&mut first, 3, 2, 42);
+        let b = Layer::new_seeded(&mut second, 3, 2, 42);
+
+        assert_eq!(
+            a.predict(&first, &[1.0, 2.0, 3.0]),
+            b.predict(&second, &[1.0, 2.0, 3.0])
+        );
+
+        let values = |nodes: &[Node]| nodes.iter().map(|n| n.value).collect::<Vec<f64>>();
+        assert_eq!(values(&first), values(&second));
+
+        let mut third: Vec<Node> = Vec::new();
+        Layer::new_seeded(&mut third, 3, 2, 7);
+        assert_ne!(values(&first), values(&third));
+    }
+
+    #[test]
+    fn scalar_engine() {
+        use crate::Scalar;
+
+        let a = Scalar::new(3.0);
+        let b = Scalar::new(2.0);
+
+        // `a` is used three times and `c` twice, so both accumulate gradient
+        // from several paths. Altogether `f = a + b - a^2`.
+        let c = &a + &b;
+        let d = &(&a * &b) + &c;
+        let e = &(&d / &c) - &a;
+        let f = &e * &c;
+
+        assert!((f.value() - -4.0).abs() < 1e-12);
+
+        f.backward();
+
+        assert!((a.gradient() - -5.0).abs() < 1e-12);
+        assert!((b.gradient() - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn scalar_long_chains() {
+        use crate::Scalar;
+
+        let x = Scalar::new(0.5);
+        let mut s = Scalar::new(0.0);
+        for _ in 0..200_000 {
+            s = &s + &x;
+        }
+        assert_eq!(s.value(), 100_000.0);
+
+        s.backward();
+        assert_eq!(x.gradient(), 200_000.0);
+
+        // Freeing the chain must not recurse once per node either.
+        drop(s);
+        assert_eq!(x.value(), 0.5);
+    }
+
+    #[test]
+    fn named_parameters() {
+        use crate::nn::Module;
+        use crate::node::{Layer, Node};
+
+        let mut nodes: Vec<Node> = Vec::new();
+        let layer = Layer::new(&mut nodes, 3, 2);
+
+        let named = layer.named_parameters();
+        let ids: Vec<usize> = named.iter().map(|&(_, id)| id).collect();
+        assert_eq!(ids, layer.parameters());
+
+        assert_eq!(named[0].0, "neuron0.weight0");
+        assert_eq!(named[3].0, "neuron0.bias");
+        assert_eq!(named[6].0, "neuron1.weight2");
+
+        assert_eq!(Module::parameter(&layer, "neuron1.bias"), Some(ids[7]));
+        assert_eq!(Module::parameter(&layer, "neuron2.bias"), None);
+    }
+
+    #[test]
+    fn sequential_stacks_modules() {
+        use crate::nn::{self, Module, Sequential};
+        use crate::{Graph, Layer};
+        use std::panic;
+
+        let mut graph = Graph::new();
+
+        let (_, nodes) = graph.parts_mut();
+        let model = Sequential::new(vec![
+            Box::new(Layer::new(nodes, 2, 3)),
+            Box::new(nn::Relu),
+            Box::new(Layer::new(nodes, 3, 1)),
+        ]);
+
+        assert_eq!(model.input_size(), Some(2));
+        assert_eq!(model.output_size(), Some(1));
+        assert_eq!(model.parameters().len(), 3 * 3 + 4);
+        assert_eq!(model.named_parameters()[9].0, "layer2.neuron0.weight0");
+
+        let x = vec![graph.leaf(1.0), graph.leaf(-1.0)];
+        assert_eq!(model.forward(&mut graph, &x).len(), 1);
+
+        let (_, nodes) = graph.parts_mut();
+        let first = Layer::new(nodes, 2, 3);
+        let second = Layer::new(nodes, 4, 1);
+        let mismatched = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            Sequential::new(vec![Box::new(first), Box::new(second)])
+        }));
+        assert!(mismatched.is_err());
+    }
+
+    #[test]
+    fn mlp_has_a_linear_output_layer() {
+        use crate::nn::{Mlp, Module};
+        use crate::node::{Activation, Node};
+
+        let mut nodes: Vec<Node> = Vec::new();
+        let mlp = Mlp::new(&mut nodes, &[2, 4, 4, 1]);
+
+        assert_eq!(mlp.layers().len(), 3);
+        assert_eq!(mlp.parameters().len(), 3 * 4 + 5 * 4 + 5);
+        assert_eq!(mlp.input_size(), Some(2));
+        assert_eq!(mlp.output_size(), Some(1));
+
+        let activations: Vec<Activation> = mlp.layers().iter().map(|l| l.activation()).collect();
+        assert_eq!(
+            activations,
+            [Activation::Relu, Activation::Relu, Activation::Linear]
+        );
+
+        // With every parameter at -1 the hidden layers clamp to zero, leaving
+        // only the output bias, which would be clamped too without a linear
+        // output layer.
+        for p in mlp.parameters() {
+            nodes[p].value = -1.0;
+        }
+        assert_eq!(mlp.predict(&nodes, &[1.0, 1.0]), [-1.0]);
+    }
+
+    #[test]
+    fn frozen_parameters() {
+        use crate::Graph;
+
+        let mut graph = Graph::new();
+
+        let w = graph.leaf(3.0);
+        let b = graph.leaf(1.0);
+        let x = graph.leaf(2.0);
+        let product = graph.mul(x, w);
+        let y = graph.add(product, b);
+
+        graph.freeze(&[w]);
+        assert!(!graph.nodes()[w].requires_grad());
+
+        graph.backward(y);
+        assert_eq!(graph.gradient(w), 0.0);
+        assert_eq!(graph.gradient(b), 1.0);
+        assert_eq!(graph.gradient(x), 3.0);
+
+        graph.sgd_step(&[w, b], 0.5);
+        assert_eq!(graph.value(w), 3.0);
+        assert_eq!(graph.value(b), 0.5);
+
+        graph.unfreeze(&[w]);
+        assert!(graph.nodes()[w].requires_grad());
+    }
+
+    #[test]
+    fn custom_operator() {
+        use crate::node::CustomOp;
+        use crate::Graph;
+        use std::sync::Arc;
+
+        // `x * y^2`, with a hand-written gradient for both inputs.
+        #[derive(Debug)]
+        struct MulSquare;
+
+        impl CustomOp for MulSquare {
+            fn name(&self) -> &'static str {
+                "mul_square"
+            }
+
+            fn forward(&self, inputs: &[f64]) -> f64 {
+                inputs[0] * inputs[1] * inputs[1]
+            }
+
+            fn backward(&self, _value: f64, gradient: f64, inputs: &[f64]) -> Vec<f64> {
+                let (x, y) = (inputs[0], inputs[1]);
+                vec![y * y * gradient, 2.0 * x * y * gradient]
+            }
+        }
+
+        let mut graph = Graph::new();
+        let x = graph.leaf(3.0);
+        let y = graph.leaf(2.0);
+        let z = graph.custom(Arc::new(MulSquare), &[x, y]);
+        assert_eq!(graph.value(z), 12.0);
+        assert_eq!(graph.stats().operator_counts["mul_square"], 1);
+
+        graph.backward(z);
+        assert_eq!(graph.gradient(x), 4.0);
+        assert_eq!(graph.gradient(y), 12.0);
+
+        graph.set_value(y, 1.0);
+        graph.forward();
+        assert_eq!(graph.value(z), 3.0);
+    }
+
+    #[test]
+    fn closure_custom_op() {
+        use crate::Graph;
+
+        let mut graph = Graph::new();
+        let x = graph.leaf(0.5);
+
+        // softsign, x / (1 + |x|)
+        let y = graph.custom_unary(
+            x,
+            |v| v / (1.0 + v.abs()),
+            |v, grad| grad / (1.0 + v.abs()).powi(2),
+        );
+        assert_eq!(graph.value(y), 0.5 / 1.5);
+
+        graph.backward(y);
+        assert_eq!(graph.gradient(x), 1.0 / 2.25);
+    }
+
+    #[test]
+    fn forward_and_backward_hooks() {
+        use crate::Graph;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let mut graph = Graph::new();
+        let x = graph.leaf(2.0);
+        let y = graph.leaf(3.0);
+        let product = graph.mul(x, y);
+        let out = graph.add(product, x);
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let log = Rc::clone(&seen);
+        graph.on_forward(product, move |id, value| log.borrow_mut().push((id, value)));
+
+        graph.set_value(y, 4.0);
+        graph.forward();
+        assert_eq!(*seen.borrow(), [(product, 8.0)]);
+
+        // Drop the gradient flowing into `x` through the product, keeping
+        // only the direct path.
+        graph.on_backward(x, |_, gradient| if gradient > 1.0 { 0.0 } else { gradient });
+        graph.backward(out);
+        assert_eq!(graph.gradient(x), 1.0);
+        assert_eq!(graph.gradient(y), 2.0);
+    }
+
+    #[test]
+    fn residual_with_sub_and_neg() {
+        use crate::Graph;
+
+        let mut graph = Graph::new();
+        let y = graph.leaf(5.0);
+        let target = graph.leaf(2.0);
+
+        let residual = graph.sub(y, target);
+        let flipped = graph.neg(residual);
+        assert_eq!(graph.value(flipped), -3.0);
+
+        graph.backward(flipped);
+        assert_eq!(graph.gradient(y), -1.0);
+        assert_eq!(graph.gradient(target), 1.0);
+    }
+
+    #[test]
+    fn division_gradients() {
+        use crate::node;
+        use std::collections::HashMap;
+
+        let mut map: node::Map = HashMap::new();
+        let mut nodes: Vec<node::Node> = Vec::new();
+
+        let a = node::new_node(&mut nodes, 3.0);
+        let b = node::new_node(&mut nodes, 4.0);
+        let (quotient, value) = node::div(&mut map, &mut nodes, a, b);
+        assert_eq!(value, 0.75);
+
+        node::backwards(&mut map, &mut nodes, quotient);
+
+        assert_eq!(nodes[a].gradient, 1.0 / 4.0);
+        assert_eq!(nodes[b].gradient, -3.0 / 16.0);
+    }
+
+    #[test]
+    fn natural_log() {
+        use crate::{Graph, GraphError};
+
+        let mut graph = Graph::new();
+        let x = graph.leaf(2.0);
+        let zero = graph.leaf(0.0);
+
+        assert_eq!(graph.try_ln(zero), Err(GraphError::NonPositive(zero)));
+
+        let clamped = graph.ln_eps(zero, 1e-6);
+        assert_eq!(graph.value(clamped), 1e-6f64.ln());
+
+        let y = graph.ln(x);
+        assert_eq!(graph.value(y), 2.0f64.ln());
+
+        graph.backward(y);
+        assert_eq!(graph.gradient(x), 0.5);
+    }
+
+    #[test]
+    fn tanh_neurons() {
+        use crate::node::{self, export, Activation, Layer, Node};
+        use std::collections::HashMap;
+
+        let mut map: node::Map = HashMap::new();
+        let mut nodes: Vec<Node> = Vec::new();
+
+        let layer = Layer::new(&mut nodes, 1, 1).with_activation(Activation::Tanh);
+        layer.load_weights(&mut nodes, &[2.0, -1.0]).unwrap();
+
+        let x = node::new_node(&mut nodes, 1.0);
+        let y = layer.connect(&mut map, &mut nodes, vec![x]);
+        assert_eq!(nodes[y[0]].value, 1.0f64.tanh());
+        assert_eq!(layer.predict(&nodes, &[1.0]), [1.0f64.tanh()]);
+
+        node::backwards(&mut map, &mut nodes, y[0]);
+        let slope = 1.0 - 1.0f64.tanh().powi(2);
+        assert!((nodes[x].gradient - 2.0 * slope).abs() < 1e-12);
+
+        let source = export::c_source(&nodes, &[&layer]).unwrap();
+        assert!(source.contains("#include <math.h>"));
+        assert!(source.contains("activate_tanh);"));
+        assert!(!source.contains("activate_relu"));
+    }
+
+    #[test]
+    fn c_export_handles_non_finite_weights_and_bad_layers() {
+        use crate::node::{export, Activation, Layer, Node};
+        use std::io::ErrorKind;
+
+        let mut nodes: Vec<Node> = Vec::new();
+        let layer = Layer::new(&mut nodes, 2, 1);
+        layer.load_weights(&mut nodes, &[1.5, -2.0, 0.25]).unwrap();
+        let source = export::c_source(&nodes, &[&layer]).unwrap();
+        assert!(!source.contains("#include <math.h>"));
+
+        layer
+            .load_weights(&mut nodes, &[f64::NAN, f64::INFINITY, f64::NEG_INFINITY])
+            .unwrap();
+        let source = export::c_source(&nodes, &[&layer]).unwrap();
+        assert!(source.contains("#include <math.h>"));
+        assert!(source.contains("    {NAN, INFINITY},"));
+        assert!(source.contains("LAYER_0_BIAS[1] = {-INFINITY};"));
+
+        let empty = Layer::new(&mut nodes, 2, 0);
+        let error = export::c_source(&nodes, &[&empty]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert_eq!(error.to_string(), "layer 0 has no neurons");
+
+        let nan_elu = Layer::new(&mut nodes, 1, 1).with_activation(Activation::Elu(f64::NAN));
+        let source = export::c_source(&nodes, &[&nan_elu, &nan_elu]).unwrap();
+        assert_eq!(source.matches("static double activate_elu").count(), 1);
+        assert!(source.contains("x > 0.0 ? x : NAN * (exp(x) - 1.0)"));
+    }
+
+    #[test]
+    fn sigmoid() {
+        use crate::Graph;
+
+        let mut graph = Graph::new();
+        let x = graph.leaf(0.0);
+        let far = graph.leaf(-1000.0);
+
+        let saturated = graph.sigmoid(far);
+        assert_eq!(graph.value(saturated), 0.0);
+
+        let y = graph.sigmoid(x);
+        assert_eq!(graph.value(y), 0.5);
+
+        graph.backward(y);
+        assert_eq!(graph.gradient(x), 0.25);
+    }
+
+    #[test]
+    fn leaky_relu() {
+        use crate::Graph;
+
+        let mut graph = Graph::new();
+        let x = graph.leaf(-2.0);
+        let y = graph.leaky_relu(x, 0.1);
+        assert_eq!(graph.value(y), -0.2);
+
+        graph.backward(y);
+        assert_eq!(graph.gradient(x), 0.1);
+    }
+
+    #[test]
+    fn gelu_gradient_matches_finite_differences() {
+        use crate::Graph;
+
+        for &x0 in &[-2.0, -0.5, 0.0, 0.7, 3.0] {
+            let mut graph = Graph::new();
+            let x = graph.leaf(x0);
+            let y = graph.gelu(x);
+            graph.backward(y);
+
+            let h = 1e-6;
+            let at = |v: f64| {
+                let mut g = Graph::new();
+                let x = g.leaf(v);
+                let y = g.gelu(x);
+                g.value(y)
+            };
+            let numeric = (at(x0 + h) - at(x0 - h)) / (2.0 * h);
+
+            assert!((graph.gradient(x) - numeric).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn elu_neurons() {
+        use crate::node::{self, export, Activation, Layer, Node};
+        use std::collections::HashMap;
+
+        let mut map: node::Map = HashMap::new();
+        let mut nodes: Vec<Node> = Vec::new();
+
+        let layer = Layer::new(&mut nodes, 1, 1).with_activation(Activation::Elu(0.5));
+        layer.load_weights(&mut nodes, &[1.0, -2.0]).unwrap();
+
+        let x = node::new_node(&mut nodes, 1.0);
+        let y = layer.connect(&mut map, &mut nodes, vec![x]);
+        let expected = 0.5 * ((-1.0f64).exp() - 1.0);
+        assert_eq!(nodes[y[0]].value, expected);
+        assert_eq!(layer.predict(&nodes, &[1.0]), [expected]);
+
+        node::backwards(&mut map, &mut nodes, y[0]);
+        assert!((nodes[x].gradient - (expected + 0.5)).abs() < 1e-12);
+
+        let other = Layer::new(&mut nodes, 1, 1).with_activation(Activation::Elu(1.0));
+        let source = export::rust_source(&nodes, &[&layer, &other]).unwrap();
+        assert!(source.contains("fn activate_elu0(x: f64)"));
+        assert!(source.contains("h, activate_elu1);"));
+    }
+
+    #[test]
+    fn rust_export_handles_non_finite_weights_and_bad_layers() {
+        use crate::node::{export, Activation, Layer, Node};
+        use std::io::ErrorKind;
+
+        let mut nodes: Vec<Node> = Vec::new();
+        let layer = Layer::new(&mut nodes, 2, 1);
+        layer
+            .load_weights(&mut nodes, &[f64::NAN, f64::INFINITY, f64::NEG_INFINITY])
+            .unwrap();
+
+        let source = export::rust_source(&nodes, &[&layer]).unwrap();
+        assert!(source.contains("    [f64::NAN, f64::INFINITY],"));
+        assert!(source.contains("[f64; 1] = [f64::NEG_INFINITY];"));
+
+        let empty = Layer::new(&mut nodes, 2, 0);
+        let error = export::rust_source(&nodes, &[&layer, &empty]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert_eq!(error.to_string(), "layer 1 has no neurons");
+
+        let wide = Layer::new(&mut nodes, 3, 1);
+        let error = export::rust_source(&nodes, &[&layer, &wide]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "layer 0 has 1 outputs, layer 1 takes 3 inputs"
+        );
+
+        assert!(export::rust_source(&nodes, &[]).is_err());
+
+        let nan_elu = Layer::new(&mut nodes, 1, 1).with_activation(Activation::Elu(f64::NAN));
+        let source = export::rust_source(&nodes, &[&nan_elu, &nan_elu]).unwrap();
+        assert_eq!(source.matches("fn activate_elu").count(), 1);
+        assert!(source.contains("{ f64::NAN * (x.exp() - 1.0) }"));
+    }
+
+    #[test]
+    fn mish_gradient_matches_finite_differences() {
+        use crate::Graph;
+
+        let at = |v: f64| {
+            let mut graph = Graph::new();
+            let x = graph.leaf(v);
+            let y = graph.mish(x);
+            graph.backward(y);
+            (graph.value(y), graph.gradient(x))
+        };
+
+        assert_eq!(at(800.0).0, 800.0);
+
+        for &x0 in &[-3.0, -0.4, 0.0, 1.2, 5.0] {
+            let h = 1e-6;
+            let numeric = (at(x0 + h).0 - at(x0 - h).0) / (2.0 * h);
+            assert!((at(x0).1 - numeric).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn prelu_learns_its_slope() {
+        use crate::nn::{Module, PRelu};
+        use crate::Graph;
+
+        let mut graph = Graph::new();
+        let (_, nodes) = graph.parts_mut();
+        let prelu = PRelu::new(nodes, 0.25);
+
+        let x = vec![graph.leaf(-2.0), graph.leaf(3.0)];
+        let y = prelu.forward(&mut graph, &x);
+        assert_eq!(graph.value(y[0]), -0.5);
+        assert_eq!(graph.value(y[1]), 3.0);
+
+        let total = graph.add(y[0], y[1]);
+        graph.backward(total);
+
+        assert_eq!(graph.gradient(prelu.slope()), -2.0);
+        assert_eq!(graph.gradient(x[0]), 0.25);
+        assert_eq!(graph.gradient(x[1]), 1.0);
+        assert_eq!(prelu.parameters(), [prelu.slope()]);
+    }
+
+    #[test]
+    fn softmax_jacobian() {
+        use crate::Graph;
+
+        let mut graph = Graph::new();
+        let xs = [graph.leaf(1.0), graph.leaf(2.0), graph.leaf(1000.0)];
+
+        let probabilities = graph.softmax(&xs).unwrap();
+        let p: Vec<f64> = probabilities.iter().map(|&id| graph.value(id)).collect();
+        assert!((p.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+        assert!(p.iter().all(|v| v.is_finite()));
+
+        // d p_k / d x_j = p_k * (delta_jk - p_j), here for k = 2.
+        graph.backward(probabilities[2]);
+        for j in 0..3 {
+            let delta = if j == 2 { 1.0 } else { 0.0 };
+            let expected = p[2] * (delta - p[j]);
+            assert!((graph.gradient(xs[j]) - expected).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn log_softmax_values_and_gradients() {
+        use crate::{Graph, GraphError};
+
+        let mut graph = Graph::new();
+        let values = [0.5, -1.0, 2.0];
+        let xs: Vec<usize> = values.iter().map(|&v| graph.leaf(v)).collect();
+        let len = graph.len();
+        let outputs = graph.log_softmax(&xs).unwrap();
+
+        // One shared normaliser plus one node per output.
+        assert_eq!(graph.len(), len + 1 + xs.len());
+        assert_eq!(graph.stats().operator_counts["logsumexp"], 1);
+
+        let normalizer = values.iter().map(|v: &f64| v.exp()).sum::<f64>().ln();
+        let p: Vec<f64> = values.iter().map(|v| (v - normalizer).exp()).collect();
+        for (&output, value) in outputs.iter().zip(values) {
+            assert!((graph.value(output) - (value - normalizer)).abs() < 1e-12);
+        }
+
+        // With output gradients g, input j receives g_j - p_j * sum(g).
+        let g = [1.0, 2.0, -0.5];
+        let seeds: Vec<(usize, f64)> = outputs.iter().copied().zip(g).collect();
+        graph.backward_multi(&seeds);
+        let total: f64 = g.iter().sum();
+        for j in 0..3 {
+            let expected = g[j] - p[j] * total;
+            assert!((graph.gradient(xs[j]) - expected).abs() < 1e-12);
+        }
+
+        assert_eq!(graph.log_softmax(&[]), Err(GraphError::EmptyInput));
+        assert_eq!(graph.softmax(&[]), Err(GraphError::EmptyInput));
+    }
+
+    #[test]
+    fn predict_proba_batch_rows_are_probabilities() {
+        use crate::node::{Layer, Node};
+
+        let mut nodes: Vec<Node> = Vec::new();
+        let binary = Layer::new_seeded(&mut nodes, 2, 1, 3);
+        let classes = Layer::new_seeded(&mut nodes, 2, 4, 4);
+        let len = nodes.len();
+
+        let inputs = vec![vec![0.5, -2.0], vec![30.0, 40.0], vec![-9.0, 1.0]];
+
+        for row in binary.predict_proba_batch(&nodes, &inputs) {
+            assert_eq!(row.len(), 1);
+            assert!((0.0..=1.0).contains(&row[0]));
+        }
+        for row in classes.predict_proba_batch(&nodes, &inputs) {
+            assert_eq!(row.len(), 4);
+            assert!(row.iter().all(|p| (0.0..=1.0).contains(p)));
+            assert!((row.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+        }
+
+        // Prediction reads the parameters without adding nodes.
+        assert_eq!(nodes.len(), len);
+    }
+
+    #[test]
+    fn square_root() {
+        use crate::{Graph, GraphError};
+
+        let mut graph = Graph::new();
+        let negative = graph.leaf(-1.0);
+        assert_eq!(
+            graph.try_sqrt(negative),
+            Err(GraphError::Negative(negative))
+        );
+
+        let x = graph.leaf(9.0);
+        let y = graph.try_sqrt(x).unwrap();
+        assert_eq!(graph.value(y), 3.0);
+
+        graph.backward(y);
+        assert_eq!(graph.gradient(x), 0.5 / 3.0);
+    }
+
+    #[test]
+    fn absolute_value() {
+        use crate::Graph;
+
+        for &(v, expected) in &[(-2.0, -1.0), (0.0, 0.0), (3.0, 1.0)] {
+            let mut graph = Graph::new();
+            let x = graph.leaf(v);
+            let y = graph.abs(x);
+            assert_eq!(graph.value(y), f64::abs(v));
+
+            graph.backward(y);
+            assert_eq!(graph.gradient(x), expected);
+        }
+    }
+
+    #[test]
+    fn hinge_loss_with_max() {
+        use crate::Graph;
+
+        // max(0, 1 - y * score) for a misclassified and a confident example.
+        for &(score, expected_value, expected_gradient) in &[(0.25, 0.75, -1.0), (2.0, 0.0, 0.0)] {
+            let mut graph = Graph::new();
+            let zero = graph.leaf(0.0);
+            let one = graph.leaf(1.0);
+            let s = graph.leaf(score);
+
+            let margin = graph.sub(one, s);
+            let loss = graph.max(zero, margin);
+            assert_eq!(graph.value(loss), expected_value);
+
+            graph.backward(loss);
+            assert_eq!(graph.gradient(s), expected_gradient);
+        }
+
+        let mut graph = Graph::new();
+        let a = graph.leaf(1.0);
+        let b = graph.leaf(2.0);
+        let smaller = graph.min(a, b);
+        graph.backward(smaller);
+        assert_eq!((graph.gradient(a), graph.gradient(b)), (1.0, 0.0));
+    }
+
+    #[test]
+    fn clamp_passes_gradient_inside_the_interval() {
+        use crate::Graph;
+
+        for &(v, expected_value, expected_gradient) in &[
+            (-3.0, -1.0, 0.0),
+            (0.5, 0.5, 1.0),
+            (1.0, 1.0, 1.0),
+            (4.0, 1.0, 0.0),
+        ] {
+            let mut graph = Graph::new();
+            let x = graph.leaf(v);
+            let y = graph.clamp(x, -1.0, 1.0);
+            assert_eq!(graph.value(y), expected_value);
+
+            graph.backward(y);
+            assert_eq!(graph.gradient(x), expected_gradient);
+        }
+    }
+
+    #[test]
+    fn trigonometry() {
+        use crate::Graph;
+
+        let mut graph = Graph::new();
+        let x = graph.leaf(0.3);
+        let sin = graph.sin(x);
+        let cos = graph.cos(x);
+
+        // sin^2 + cos^2 is constant, so its gradient vanishes.
+        let sin2 = graph.mul(sin, sin);
+        let cos2 = graph.mul(cos, cos);
+        let total = graph.add(sin2, cos2);
+        assert!((graph.value(total) - 1.0).abs() < 1e-12);
+
+        graph.backward(total);
+        assert!(graph.gradient(x).abs() < 1e-12);
+        assert_eq!(graph.gradient(sin), 2.0 * 0.3f64.sin());
+    }
+
+    #[test]
+    fn logsumexp_reduction() {
+        use crate::Graph;
+
+        let mut graph = Graph::new();
+        let xs = [graph.leaf(1000.0), graph.leaf(1000.0), graph.leaf(-5.0)];
+
+        let total = graph.logsumexp(&xs);
+        let expected = 1000.0 + (2.0 + (-1005.0f64).exp()).ln();
+        assert!((graph.value(total) - expected).abs() < 1e-9);
+
+        graph.backward(total);
+        assert!((graph.gradient(xs[0]) - 0.5).abs() < 1e-12);
+        assert!((graph.gradient(xs[1]) - 0.5).abs() < 1e-12);
+        assert!(graph.gradient(xs[2]) < 1e-300);
+
+        let infinite = [graph.leaf(f64::NEG_INFINITY), graph.leaf(f64::NEG_INFINITY)];
+        let total = graph.logsumexp(&infinite);
+        assert_eq!(graph.value(total), f64::NEG_INFINITY);
+    }
+
+    #[test]
+    fn select_routes_gradient_to_the_taken_branch() {
+        use crate::Graph;
+
+        // Piecewise f(x) = x^2 for x > 1, 2x otherwise.
+        let mut graph = Graph::new();
+        let x = graph.leaf(3.0);
+        let one = graph.leaf(1.0);
+        let two = graph.leaf(2.0);
+
+        let condition = graph.gt(x, one);
+        let square = graph.mul(x, x);
+        let double = graph.mul(two, x);
+        let y = graph.select(condition, square, double);
+        assert_eq!(graph.value(y), 9.0);
+
+        graph.backward(y);
+        assert_eq!(graph.gradient(x), 6.0);
+        assert_eq!(graph.gradient(double), 0.0);
+
+        graph.set_value(x, 0.5);
+        graph.forward();
+        assert_eq!(graph.value(y), 1.0);
+    }
+
+    #[test]
+    fn sign_with_straight_through_estimators() {
+        use crate::node::Ste;
+        use crate::Graph;
+
+        let estimators = [
+            (Ste::None, 0.0, 0.0),
+            (Ste::Identity, 1.0, 1.0),
+            (Ste::Clipped, 1.0, 0.0),
+        ];
+        for (ste, small_gradient, large_gradient) in estimators {
+            let mut graph = Graph::new();
+            let small = graph.leaf(-0.5);
+            let large = graph.leaf(2.0);
+
+            let a = graph.sign(small, ste);
+            let b = graph.sign(large, ste);
+            let y = graph.add(a, b);
+            assert_eq!(graph.value(y), 0.0);
+
+            graph.backward(y);
+            assert_eq!(graph.gradient(small), small_gradient);
+            assert_eq!(graph.gradient(large), large_gradient);
+        }
+    }
+
+    #[test]
+    fn round_and_threshold_with_straight_through_estimators() {
+        use crate::node::Ste;
+        use crate::Graph;
+
+        // The clipped estimator passes within 1.0 of zero for `round` and of
+        // the threshold (0.5 here) for `hard_threshold`.
+        let estimators = [
+            (Ste::None, 0.0, 0.0),
+            (Ste::Identity, 1.0, 1.0),
+            (Ste::Clipped, 1.0, 0.0),
+        ];
+        for (ste, near_gradient, far_gradient) in estimators {
+            let mut graph = Graph::new();
+            let near = graph.leaf(0.6);
+            let far = graph.leaf(1.7);
+
+            let a = graph.round(near, ste);
+            let b = graph.round(far, ste);
+            assert_eq!((graph.value(a), graph.value(b)), (1.0, 2.0));
+            let y = graph.add(a, b);
+            graph.backward(y);
+            assert_eq!(graph.gradient(near), near_gradient);
+            assert_eq!(graph.gradient(far), far_gradient);
+
+            let mut graph = Graph::new();
+            let near = graph.leaf(1.2);
+            let far = graph.leaf(-0.8);
+
+            let a = graph.hard_threshold(near, 0.5, ste);
+            let b = graph.hard_threshold(far, 0.5, ste);
+            assert_eq!((graph.value(a), graph.value(b)), (1.0, 0.0));
+            let y = graph.add(a, b);
+            graph.backward(y);
+            assert_eq!(graph.gradient(near), near_gradient);
+            assert_eq!(graph.gradient(far), far_gradient);
+        }
+    }
+
+    #[test]
+    fn scalar_constants_are_not_trained() {
+        use crate::{Graph, Value};
+        use std::cell::RefCell;
+
+        // y = 3 * (x + 1) at x = 1.
+        let mut graph = Graph::new();
+        let x = graph.leaf(1.0);
+        let shifted = graph.add_scalar(x, 1.0);
+        let y = graph.mul_scalar(shifted, 3.0);
+        assert_eq!(graph.value(y), 6.0);
+
+        graph.backward(y);
+        assert_eq!(graph.gradient(x), 3.0);
+        let trainable = graph.nodes().iter().filter(|n| n.requires_grad());
+        assert_eq!(trainable.count(), 3);
+
+        let squared = graph.pow_scalar(x, 2.0);
+        assert_eq!(graph.value(squared), 1.0);
+
+        let graph = RefCell::new(Graph::new());
+        let x = Value::new(&graph, 1.0);
+        let y = (x + 1.0).pow_scalar(2.0) * 3.0;
+        assert_eq!(y.data(), 12.0);
+    }
+
+    #[test]
+    fn pow_gradient_reaches_base_and_exponent() {
+        use crate::Graph;
+
+        let mut graph = Graph::new();
+        let a = graph.leaf(3.0);
+        let b = graph.leaf(2.0);
+        let y = graph.pow(a, b);
+        assert_eq!(graph.value(y), 9.0);
+
+        graph.backward(y);
+        assert_eq!(graph.gradient(a), 6.0);
+        assert!((graph.gradient(b) - 9.0 * 3.0f64.ln()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn sum_fans_gradient_out_to_every_input() {
+        use crate::Graph;
+
+        let mut graph = Graph::new();
+        let xs: Vec<usize> = (1..=4).map(|i| graph.leaf(i as f64)).collect();
+        let total = graph.sum(&[xs[0], xs[1], xs[2], xs[3], xs[1]]);
+        assert_eq!(graph.value(total), 12.0);
+
+        graph.backward(total);
+        let gradients: Vec<f64> = xs.iter().map(|&x| graph.gradient(x)).collect();
+        assert_eq!(gradients, vec![1.0, 2.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn mean_splits_gradient_evenly() {
+        use crate::Graph;
+
+        let mut graph = Graph::new();
+        let xs: Vec<usize> = [1.0, 2.0, 6.0, 7.0]
+            .iter()
+            .map(|&x| graph.leaf(x))
+            .collect();
+        let average = graph.mean(&xs).unwrap();
+        assert_eq!(graph.value(average), 4.0);
+
+        graph.backward(average);
+        assert!(xs.iter().all(|&x| graph.gradient(x) == 0.25));
+    }
+
+    #[test]
+    fn reductions_over_no_inputs() {
+        use crate::node::CustomOp;
+        use crate::{Graph, GraphError};
+        use std::sync::Arc;
+
+        #[derive(Debug)]
+        struct Pi;
+
+        impl CustomOp for Pi {
+            fn name(&self) -> &'static str {
+                "pi"
+            }
+
+            fn forward(&self, _inputs: &[f64]) -> f64 {
+                std::f64::consts::PI
+            }
+
+            fn backward(&self, _value: f64, _gradient: f64, _inputs: &[f64]) -> Vec<f64> {
+                Vec::new()
+            }
+        }
+
+        let mut graph = Graph::new();
+        let total = graph.sum(&[]);
+        let product = graph.prod(&[]);
+        let dot = graph.dot(&[], &[]);
+        let normalizer = graph.logsumexp(&[]);
+        let kl = graph.gaussian_kl(&[], &[]);
+        let pi = graph.custom(Arc::new(Pi), &[]);
+        assert_eq!(graph.value(total), 0.0);
+        assert_eq!(graph.value(product), 1.0);
+        assert_eq!(graph.value(dot), 0.0);
+        assert_eq!(graph.value(normalizer), f64::NEG_INFINITY);
+        assert_eq!(graph.value(kl), 0.0);
+        assert_eq!(graph.value(pi), std::f64::consts::PI);
+        assert!(!graph.nodes()[total].requires_grad());
+        assert_eq!(graph.mean(&[]), Err(GraphError::EmptyInput));
+        assert_eq!(graph.log_softmax(&[]), Err(GraphError::EmptyInput));
+        assert_eq!(graph.softmax(&[]), Err(GraphError::EmptyInput));
+
+        let x = graph.leaf(3.0);
+        let shifted = graph.add(x, total);
+        let y = graph.mul(shifted, product);
+        graph.backward(y);
+        assert_eq!(graph.gradient(x), 1.0);
+    }
+
+    #[test]
+    fn layer_connect_uses_every_weighted_input() {
+        use crate::{Activation, Layer, Map};
+
+        let mut map = Map::new();
+        let mut nodes = Vec::new();
+        let layer = Layer::new_seeded(&mut nodes, 3, 2, 7).with_activation(Activation::Linear);
+        layer
+            .load_weights(&mut nodes, &[1.0, 2.0, 3.0, 0.5, -1.0, 0.0, 1.0, -2.0])
+            .unwrap();
+
+        let x = [1.0, -1.0, 2.0];
+        let inputs: Vec<usize> = x.iter().map(|&v| crate::new_node(&mut nodes, v)).collect();
+        let outputs = layer.connect(&mut map, &mut nodes, inputs);
+
+        let connected: Vec<f64> = outputs.iter().map(|&o| nodes[o].value).collect();
+        assert_eq!(connected, vec![5.5, -1.0]);
+        assert_eq!(connected, layer.predict(&nodes, &x));
+    }
+
+    #[test]
+    fn vecops_apply_elementwise() {
+        use crate::node::vecops;
+        use crate::Graph;
+
+        let mut graph = Graph::new();
+        let xs: Vec<usize> = [1.0, -2.0, 3.0].iter().map(|&x| graph.leaf(x)).collect();
+        let ys: Vec<usize> = [4.0, 5.0, -6.0].iter().map(|&y| graph.leaf(y)).collect();
+
+        let (map, nodes) = graph.parts_mut();
+        let products = vecops::mul(map, nodes, &xs, &ys);
+        let rectified = vecops::relu(map, nodes, &products);
+        let doubled = vecops::scale(map, nodes, &rectified, 2.0);
+
+        let values: Vec<f64> = doubled.iter().map(|&z| graph.value(z)).collect();
+        assert_eq!(values, vec![8.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn prod_gradient_is_product_of_the_others() {
+        use crate::Graph;
+
+        let mut graph = Graph::new();
+        let xs: Vec<usize> = [2.0, 0.0, 3.0, 4.0]
+            .iter()
+            .map(|&x| graph.leaf(x))
+            .collect();
+        let product = graph.prod(&xs);
+        assert_eq!(graph.value(product), 0.0);
+
+        graph.backward(product);
+        let gradients: Vec<f64> = xs.iter().map(|&x| graph.gradient(x)).collect();
+        assert_eq!(gradients, vec![0.0, 24.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn cosine_similarity_of_vectors() {
+        use crate::Graph;
+
+        let mut graph = Graph::new();
+        let xs: Vec<usize> = [3.0, 0.0].iter().map(|&x| graph.leaf(x)).collect();
+        let ys: Vec<usize> = [1.0, 1.0].iter().map(|&y| graph.leaf(y)).collect();
+        let similarity = graph.cosine_similarity(&xs, &ys);
+        assert!((graph.value(similarity) - 0.5f64.sqrt()).abs() < 1e-12);
+
+        // Only the direction matters: scaling xs leaves the value unchanged,
+        // so the gradient along xs is zero.
+        graph.backward(similarity);
+        assert!(graph.gradient(xs[0]).abs() < 1e-12);
+        assert!((graph.gradient(xs[1]) - 0.5f64.sqrt() / 3.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn backward_completes_gradients_of_reused_nodes() {
+        use crate::Graph;
+
+        // y = t + 2t with t = x^2: t is reached directly from y and again
+        // through u, and must collect both before passing its gradient on.
+        let mut graph = Graph::new();
+        let x = graph.leaf(3.0);
+        let two = graph.leaf(2.0);
+        let t = graph.mul(x, x);
+        let u = graph.mul(t, two);
+        let y = graph.add(t, u);
+
+        graph.backward(y);
+        assert_eq!(graph.gradient(t), 3.0);
+        assert_eq!(graph.gradient(x), 18.0);
+    }
+
+    #[test]
+    fn backward_from_an_intermediate_node() {
+        use crate::Graph;
+
+        let mut graph = Graph::new();
+        let x = graph.leaf(3.0);
+        let square = graph.mul(x, x);
+        let cube = graph.mul(square, x);
+
+        graph.backward(square);
+        assert_eq!(graph.gradient(x), 6.0);
+        assert_eq!(graph.gradient(cube), 0.0);
+
+        let mut graph = Graph::new();
+        let x = graph.leaf(3.0);
+        let cube = graph.pow_scalar(x, 3.0);
+        graph.leaf(1.0);
+
+        graph.backward_parallel(cube, 2);
+        assert_eq!(graph.gradient(x), 27.0);
+    }
+
+    #[test]
+    fn backward_from_several_seeded_outputs() {
+        use crate::{Activation, Layer, Map};
+
+        let mut map = Map::new();
+        let mut nodes = Vec::new();
+        let layer = Layer::new_seeded(&mut nodes, 2, 2, 3).with_activation(Activation::Linear);
+        layer
+            .load_weights(&mut nodes, &[1.0, 2.0, 0.0, 3.0, -1.0, 0.0])
+            .unwrap();
+
+        let x: Vec<usize> = [2.0, 5.0]
+            .iter()
+            .map(|&v| crate::new_node(&mut nodes, v))
+            .collect();
+        let y = layer.connect(&mut map, &mut nodes, x.clone());
+
+        // Loss 0.5 * y0^2 + y1, whose gradient is y0 for the first output and
+        // 1 for the second.
+        let y0 = nodes[y[0]].value;
+        crate::node::backwards_multi(&mut map, &mut nodes, &[(y[0], y0), (y[1], 1.0)]);
+
+        assert_eq!(y0, 12.0);
+        assert_eq!(nodes[x[0]].gradient, 12.0 * 1.0 + 3.0);
+        assert_eq!(nodes[x[1]].gradient, 12.0 * 2.0 - 1.0);
+    }
+
+    #[test]
+    fn zero_grad_between_backward_passes() {
+        use crate::Graph;
+
+        let mut graph = Graph::new();
+        let w = graph.leaf(2.0);
+        let x = graph.leaf(3.0);
+        let y = graph.mul(w, x);
+
+        graph.backward(y);
+        graph.backward(y);
+        assert_eq!(graph.gradient(w), 6.0);
+
+        graph.zero_grad_of(&[w]);
+        assert_eq!(graph.gradient(w), 0.0);
+        assert_eq!(graph.gradient(x), 4.0);
+
+        graph.zero_grad();
+        graph.backward(y);
+        assert_eq!(graph.gradient(w), 3.0);
+        assert_eq!(graph.gradient(x), 2.0);
+    }
+
+    #[test]
+    fn sample_normal_trains_only_mu_and_log_var() {
+        use crate::rng::seeded_rng;
+        use crate::Graph;
+
+        let mut graph = Graph::new();
+        let mu = graph.leaf(0.5);
+        let log_var = graph.leaf(-1.0);
+        let z = graph.sample_normal(mu, log_var, &mut seeded_rng(7));
+        graph.backward(z);
+
+        let std_dev = (-0.5f64).exp();
+        let eps = (graph.value(z) - 0.5) / std_dev;
+        assert_eq!(graph.gradient(mu), 1.0);
+        assert!((graph.gradient(log_var) - 0.5 * std_dev * eps).abs() < 1e-12);
+
+        let trained: Vec<usize> = (0..graph.len())
+            .filter(|&id| graph.nodes()[id].requires_grad() && !graph.map().contains_key(&id))
+            .collect();
+        assert_eq!(trained, vec![mu, log_var]);
+    }
+
+    #[test]
+    fn cond_follows_the_predicate_on_every_pass() {
+        use crate::node::{self, backwards, Map};
+        use crate::Graph;
+
+        let mut graph = Graph::new();
+        let x = graph.leaf(2.0);
+        let zero = graph.constant(0.0);
+        let positive = graph.gt(x, zero);
+
+        let y = graph.cond(positive, |g| g.mul(x, x), |g| g.neg(x));
+        assert_eq!(graph.value(y), 4.0);
+        graph.backward(y);
+        assert_eq!(graph.gradient(x), 4.0);
+
+        graph.set_value(x, -3.0);
+        graph.forward();
+        assert_eq!(graph.value(positive), 0.0);
+        assert_eq!(graph.value(y), 3.0);
+        graph.zero_grad();
+        graph.backward(y);
+        assert_eq!(graph.gradient(x), -1.0);
+
+        // The branch not taken is never backpropagated through, so its NaN
+        // derivative at a negative input does not reach `x`.
+        let root_of_x = graph.sqrt(x);
+        let square = graph.mul(x, x);
+        let root = graph.cond(positive, |_| root_of_x, |_| square);
+        assert!(graph.value(root_of_x).is_nan());
+        graph.zero_grad();
+        graph.backward(root);
+        assert_eq!(graph.gradient(x), -6.0);
+        graph.zero_grad();
+        graph.backward_parallel(root, 2);
+        assert_eq!(graph.gradient(x), -6.0);
+
+        let mut map = Map::new();
+        let mut nodes = Vec::new();
+        let x = node::new_node(&mut nodes, -1.0);
+        let y = node::new_node(&mut nodes, 5.0);
+        let z = node::cond(
+            &mut map,
+            &mut nodes,
+            x,
+            |map, nodes| node::mul(map, nodes, x, y).0,
+            |map, nodes| node::add(map, nodes, x, y).0,
+        );
+        assert_eq!(nodes[z].value, 4.0);
+
+        backwards(&mut map, &mut nodes, z);
+        assert_eq!((nodes[x].gradient, nodes[y].gradient), (1.0, 1.0));
+    }
+
+    #[test]
+    fn scan_backpropagates_through_every_step() {
+        use crate::node::{self, backwards, Map};
+        use crate::Graph;
+
+        // h_{t+1} = w * h_t + t, so h_3 = w^3 h_0 + w + 2.
+        let mut graph = Graph::new();
+        let w = graph.leaf(2.0);
+        let h = graph.leaf(1.0);
+        let states = graph.scan(3, vec![h], |g, step, state| {
+            let scaled = g.mul(w, state[0]);
+            vec![g.add_scalar(scaled, step as f64)]
+        });
+        let values: Vec<f64> = states.iter().map(|s| graph.value(s[0])).collect();
+        assert_eq!(values, vec![2.0, 5.0, 12.0]);
+
+        graph.backward(states[2][0]);
+        assert_eq!(graph.gradient(w), 3.0 * 4.0 + 1.0);
+        assert_eq!(graph.gradient(h), 8.0);
+
+        let mut map = Map::new();
+        let mut nodes = Vec::new();
+        let w = node::new_node(&mut nodes, 2.0);
+        let h = node::new_node(&mut nodes, 1.0);
+        let states = node::scan(&mut map, &mut nodes, 3, vec![h], |map, nodes, _, state| {
+            vec![node::mul(map, nodes, w, state[0]).0]
+        });
+        assert_eq!(nodes[states[2][0]].value, 8.0);
+
+        backwards(&mut map, &mut nodes, states[2][0]);
+        assert_eq!(nodes[w].gradient, 12.0);
+    }
+
+    #[test]
+    fn backward_override_passes_gradient_through_a_mask() {
+        use crate::node::{self, backwards, backwards_with_overrides, Map, Overrides};
+
+        let mut map = Map::new();
+        let mut nodes = Vec::new();
+        let x = node::new_node(&mut nodes, 0.4);
+        let w = node::new_node(&mut nodes, 3.0);
+        let zero = node::constant(&mut nodes, 0.0);
+        let (mask, _) = node::gt(&mut map, &mut nodes, x, zero);
+        let (y, _) = node::mul(&mut map, &mut nodes, mask, w);
+
+        // The mask blocks the gradient on its own.
+        backwards(&mut map, &mut nodes, y);
+        assert_eq!(nodes[x].gradient, 0.0);
+        assert_eq!(nodes[w].gradient, 1.0);
+
+        // A straight-through surrogate for the mask.
+        let mut overrides: Overrides = Overrides::new();
+        overrides.insert(mask, Box::new(|node, _| vec![node.gradient, 0.0]));
+        node::zero_grad(&mut nodes);
+        backwards_with_overrides(&mut map, &mut nodes, y, &overrides);
+        assert_eq!(nodes[x].gradient, 3.0);
+        assert_eq!(nodes[w].gradient, 1.0);
+    }
+
+    #[test]
+    fn top_k_selects_the_largest_inputs() {
+        use crate::Graph;
+
+        let mut graph = Graph::new();
+        let xs: Vec<usize> = [0.5, 3.0, -1.0, 2.0].map(|v| graph.leaf(v)).to_vec();
+
+        let (selected, positions) = graph.top_k(&xs, 2);
+        assert_eq!(positions, vec![1, 3]);
+        let values: Vec<f64> = selected.iter().map(|&s| graph.value(s)).collect();
+        assert_eq!(values, vec![3.0, 2.0]);
+
+        let total = graph.sum(&selected);
+        graph.backward(total);
+        let gradients: Vec<f64> = xs.iter().map(|&x| graph.gradient(x)).collect();
+        assert_eq!(gradients, vec![0.0, 1.0, 0.0, 1.0]);
+
+        let (selected, positions) = graph.top_k(&xs, 10);
+        assert_eq!(positions, vec![1, 3, 0, 2]);
+        assert_eq!(selected.len(), 4);
+
+        assert_eq!(graph.top_k(&xs, 0), (vec![], vec![]));
+    }
+
+    #[test]
+    fn cumsum_gradient_is_the_reverse_cumulative_sum() {
+        use crate::Graph;
+
+        let mut graph = Graph::new();
+        let xs: Vec<usize> = [1.0, 2.0, 3.0, 4.0].map(|v| graph.leaf(v)).to_vec();
+        let sums = graph.cumsum(&xs);
+        let values: Vec<f64> = sums.iter().map(|&s| graph.value(s)).collect();
+        assert_eq!(values, vec![1.0, 3.0, 6.0, 10.0]);
+
+        // Output gradients g give input gradients g_i + ... + g_{n-1}.
+        let seeds: Vec<(usize, f64)> = sums
+            .iter()
+            .copied()
+            .zip([1.0, 10.0, 100.0, 1000.0])
+            .collect();
+        graph.backward_multi(&seeds);
+        let gradients: Vec<f64> = xs.iter().map(|&x| graph.gradient(x)).collect();
+        assert_eq!(gradients, vec![1111.0, 1110.0, 1100.0, 1000.0]);
+
+        assert!(graph.cumsum(&[]).is_empty());
+    }
+
+    #[test]
+    fn gaussian_kl_matches_the_closed_form() {
+        use crate::Graph;
+
+        let (mu, log_var) = ([0.5, -1.0], [0.2, -0.3]);
+        let mut graph = Graph::new();
+        let mus: Vec<usize> = mu.iter().map(|&m| graph.leaf(m)).collect();
+        let log_vars: Vec<usize> = log_var.iter().map(|&lv| graph.leaf(lv)).collect();
+        let kl = graph.gaussian_kl(&mus, &log_vars);
+
+        let expected: f64 = mu
+            .iter()
+            .zip(log_var)
+            .map(|(m, lv)| 0.5 * (lv.exp() + m * m - 1.0 - lv))
+            .sum();
+        assert!((graph.value(kl) - expected).abs() < 1e-12);
+
+        let params: Vec<usize> = mus.iter().chain(&log_vars).copied().collect();
+        assert!(graph.gradcheck(kl, &params, 1e-6, 1e-6).is_empty());
+    }
+
+    #[test]
+    fn vae_step_lowers_the_elbo_loss() {
+        use crate::node::{self, Map, Node};
+        use crate::rng::seeded_rng;
+        use crate::vae::Vae;
+
+        let mut nodes: Vec<Node> = Vec::new();
+        let vae = Vae::new_with_rng(&mut nodes, 3, 4, 2, &mut seeded_rng(1));
+        let parameters = vae.parameters();
+        let targets = [0.2, -0.4, 0.9];
+        let len = nodes.len();
+
+        // The same seed draws the same noise, so both losses are of one sample.
+        let loss_at = |nodes: &mut Vec<Node>| {
+            nodes.truncate(len);
+            let mut map = Map::new();
+            let x: Vec<usize> = targets.iter().map(|&t| node::constant(nodes, t)).collect();
+            let output = vae.forward(&mut map, nodes, x, &mut seeded_rng(2));
+            assert_eq!(output.z.len(), 2);
+            let loss = vae.loss(&mut map, nodes, &output, &targets);
+            (map, loss)
+        };
+
+        let (mut map, loss) = loss_at(&mut nodes);
+        let before = nodes[loss].value;
+        node::zero_grad(&mut nodes);
+        node::backwards(&mut map, &mut nodes, loss);
+        assert!(parameters.iter().any(|&p| nodes[p].gradient != 0.0));
+        node::sgd_step(&mut nodes, &parameters, 0.01);
+
+        let (_, loss) = loss_at(&mut nodes);
+        assert!(nodes[loss].value < before);
+    }
+
+    #[cfg(feature = "ndarray")]
+    #[test]
+    fn ndarray_round_trip() {
+        use crate::node::array::{nodes_from_array1, values_to_array1};
+        use crate::node::{Layer, Node};
+        use ndarray::{array, Array2};
+
+        let mut nodes: Vec<Node> = Vec::new();
+        let values = array![1.5, -2.0, 0.25];
+        let ids = nodes_from_array1(&mut nodes, &values);
+        assert_eq!(values_to_array1(&nodes, &ids), values);
+
+        let weights = array![[1.0, 2.0, 3.0], [-4.0, 5.0, -6.0]];
+        let bias = array![0.5, -0.5];
+        let layer = Layer::from_array2(&mut nodes, &weights, &bias);
+        assert_eq!(layer.weights_to_array2(&nodes), weights);
+        assert_eq!(layer.bias_to_array1(&nodes), bias);
+
+        let empty = Layer::from_array2(&mut nodes, &Array2::zeros((0, 3)), &array![]);
+        assert_eq!(empty.weights_to_array2(&nodes).dim(), (0, 0));
+    }
+
+    #[cfg(feature = "nalgebra")]
+    #[test]
+    fn nalgebra_round_trip_and_dense_forward() {
+        use crate::node::linalg::{nodes_from_dvector, values_to_dvector};
+        use crate::node::{Layer, Node};
+        use nalgebra::{dmatrix, dvector};
+
+        let mut nodes: Vec<Node> = Vec::new();
+        let values = dvector![1.5, -2.0, 0.25];
+        let ids = nodes_from_dvector(&mut nodes, &values);
+        assert_eq!(values_to_dvector(&nodes, &ids), values);
+
+        let weights = dmatrix![1.0, 2.0, 3.0; -4.0, 5.0, -6.0];
+        let bias = dvector![0.5, -0.5];
+        let layer = Layer::from_dmatrix(&mut nodes, &weights, &bias);
+        assert_eq!(layer.weights_to_dmatrix(&nodes), weights);
+        assert_eq!(layer.bias_to_dvector(&nodes), bias);
+
+        // The dense snapshot computes what the layer does, relu included.
+        let dense = layer.to_dense(&nodes);
+        let x = [1.0, 1.0, 1.0];
+        let expected = layer.predict(&nodes, &x);
+        assert_eq!(expected, vec![6.5, 0.0]);
+        assert_eq!(
+            dense.forward(&dvector![1.0, 1.0, 1.0]).as_slice(),
+            &expected[..]
+        );
+    }
+
+    #[test]
+    fn layer_init_from_reports_bad_input() {
+        use crate::node::{Layer, Node};
+        use std::io::{self, ErrorKind, Read};
+
+        struct Failing;
+
+        impl Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(ErrorKind::UnexpectedEof, "stream ended"))
+            }
+        }
+
+        let mut nodes: Vec<Node> = Vec::new();
+        let layer = Layer::new_seeded(&mut nodes, 1, 2, 5);
+        let before: Vec<f64> = nodes.iter().map(|n| n.value).collect();
+
+        let error = layer.init_from(&mut nodes, Failing).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+
+        let error = layer.init_from(&mut nodes, "1 2 3".as_bytes()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "got 3 values, layer has 4 parameters");
+
+        let error = layer
+            .init_from(&mut nodes, "1 2 3 4 5".as_bytes())
+            .unwrap_err();
+        assert_eq!(error.to_string(), "got 5 values, layer has 4 parameters");
+
+        let error = layer
+            .init_from(&mut nodes, "1 2 x 4".as_bytes())
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "'x' is not a number");
+
+        let after: Vec<f64> = nodes.iter().map(|n| n.value).collect();
+        assert_eq!(after, before);
+
+        let error = layer.load_weights(&mut nodes, &[1.0]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "got 1 values, layer has 4 parameters");
+
+        let after: Vec<f64> = nodes.iter().map(|n| n.value).collect();
+        assert_eq!(after, before);
+
+        layer.init_from(&mut nodes, "1 2\n3 4".as_bytes()).unwrap();
+        let values: Vec<f64> = layer.parameters().iter().map(|&p| nodes[p].value).collect();
+        assert_eq!(values, vec![1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn mlp_load_weights_and_init_from() {
+        use crate::nn::Mlp;
+        use crate::node::Node;
+
+        let mut nodes: Vec<Node> = Vec::new();
+        let mlp = Mlp::new(&mut nodes, &[2, 2, 1]);
+        let values = |nodes: &[Node]| -> Vec<f64> {
+            mlp.parameters().iter().map(|&p| nodes[p].value).collect()
+        };
+
+        // Layer 0 (two neurons of two weights and a bias), then layer 1.
+        let flat = [1.0, 2.0, 0.5, -1.0, 0.0, 0.25, 3.0, -3.0, 1.0];
+        mlp.load_weights(&mut nodes, &flat).unwrap();
+        assert_eq!(values(&nodes), flat);
+        assert_eq!(mlp.predict(&nodes, &[1.0, 1.0]), [3.5 * 3.0 + 1.0]);
+
+        let error = mlp.load_weights(&mut nodes, &flat[1..]).unwrap_err();
+        assert_eq!(error.to_string(), "got 8 values, network has 9 parameters");
+        assert_eq!(values(&nodes), flat);
+
+        mlp.init_from(&mut nodes, "9 8 7 6 5 4\n3 2 1".as_bytes())
+            .unwrap();
+        assert_eq!(
+            values(&nodes),
+            [9.0, 8.0, 7.0, 6.0, 5.0, 4.0, 3.0, 2.0, 1.0]
+        );
+
+        let error = mlp.init_from(&mut nodes, "1 2 3".as_bytes()).unwrap_err();
+        assert_eq!(error.to_string(), "got 3 values, network has 9 parameters");
+        assert_eq!(
+            values(&nodes),
+            [9.0, 8.0, 7.0, 6.0, 5.0, 4.0, 3.0, 2.0, 1.0]
+        );
+    }
+
+    #[test]
+    fn eval_recomputes_only_the_target_subgraph() {
+        use crate::Graph;
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let mut graph = Graph::new();
+        let x = graph.leaf(1.0);
+        let y = graph.leaf(2.0);
+        let doubled = graph.mul_scalar(x, 2.0);
+        let target = graph.add(doubled, x);
+        let other = graph.add_scalar(y, 1.0);
+        let after = graph.mul(target, y);
+
+        let recomputed = Rc::new(Cell::new(0));
+        for id in [other, after] {
+            let recomputed = recomputed.clone();
+            graph.on_forward(id, move |_, _| recomputed.set(recomputed.get() + 1));
+        }
+
+        graph.set_value(x, 5.0);
+        graph.set_value(y, 10.0);
+        assert_eq!(graph.eval(target), 15.0);
+        assert_eq!(graph.value(doubled), 10.0);
+
+        // Nodes outside the target's inputs keep their stale values.
+        assert_eq!(graph.value(other), 3.0);
+        assert_eq!(graph.value(after), 6.0);
+        assert_eq!(recomputed.get(), 0);
+
+        graph.forward();
+        assert_eq!(graph.value(other), 11.0);
+        assert_eq!(graph.value(after), 150.0);
+        assert_eq!(recomputed.get(), 2);
+    }
+
+    #[test]
+    fn layer_load_npy_reads_linear_weights() {
+        use crate::node::{Layer, Node};
+        use std::fs;
+        use std::io::ErrorKind;
+        use std::path::PathBuf;
+
+        // A version 1.0 `.npy` file of little-endian f64s, as `numpy.save`
+        // writes it.
+        fn write_npy(name: &str, shape: &str, data: &[f64]) -> PathBuf {
+            let mut header = format!(
+                "{{'descr': '<f8', 'fortran_order': False, 'shape': {}, }}",
+                shape
+            );
+            while (10 + header.len() + 1) % 64 != 0 {
+                header.push(' ');
+            }
+            header.push('\n');
+
+            let mut bytes = b"\x93NUMPY\x01\x00".to_vec();
+            bytes.extend((header.len() as u16).to_le_bytes());
+            bytes.extend(header.as_bytes());
+            for value in data {
+                bytes.extend(value.to_le_bytes());
+            }
+
+            let path =
+                std::env::temp_dir().join(format!("rustygrad-{}-{}", std::process::id(), name));
+            fs::write(&path, bytes).unwrap();
+            path
+        }
+
+        let weights = write_npy("weights.npy", "(2, 3)", &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        let bias = write_npy("bias.npy", "(2,)", &[-1.0, -2.0]);
+        let wrong = write_npy("wrong.npy", "(3, 2)", &[0.0; 6]);
+
+        let mut nodes: Vec<Node> = Vec::new();
+        let layer = Layer::new_seeded(&mut nodes, 3, 2, 9);
+        layer.load_npy(&mut nodes, &weights, &bias).unwrap();
+        let values: Vec<f64> = layer.parameters().iter().map(|&p| nodes[p].value).collect();
+        assert_eq!(values, vec![1.0, 2.0, 3.0, -1.0, 4.0, 5.0, 6.0, -2.0]);
+
+        let error = layer.load_npy(&mut nodes, &wrong, &bias).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(
+            error.to_string(),
+            "weights have shape [3, 2], layer expects [2, 3]"
+        );
+
+        let error = layer.load_npy(&mut nodes, &weights, &weights).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "bias has shape [2, 3], layer expects [2]"
+        );
+
+        for path in [weights, bias, wrong] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn mlp_load_npz_reads_savez_archives() {
+        use crate::nn::Mlp;
+        use crate::node::Node;
+        use crate::npy;
+        use std::fs;
+
+        fn npy_bytes(shape: &str, data: &[f64]) -> Vec<u8> {
+            let mut header = format!(
+                "{{'descr': '<f8', 'fortran_order': False, 'shape': {}, }}",
+                shape
+            );
+            while (10 + header.len() + 1) % 64 != 0 {
+                header.push(' ');
+            }
+            header.push('\n');
+
+            let mut bytes = b"\x93NUMPY\x01\x00".to_vec();
+            bytes.extend((header.len() as u16).to_le_bytes());
+            bytes.extend(header.as_bytes());
+            for value in data {
+                bytes.extend(value.to_le_bytes());
+            }
+            bytes
+        }
+
+        // A zip archive of `entries` with the given compression method, laid
+        // out as `numpy.savez` writes it. The CRCs are left at zero, since the
+        // reader does not check them.
+        fn npz(entries: &[(&str, Vec<u8>)], method: u16) -> Vec<u8> {
+            let (mut bytes, mut directory) = (Vec::new(), Vec::new());
+            for (name, data) in entries {
+                let offset = bytes.len() as u32;
+                let header = |signature: u32, central: bool| {
+                    let mut h = signature.to_le_bytes().to_vec();
+                    if central {
+                        h.extend(20u16.to_le_bytes());
+                    }
+                    h.extend(20u16.to_le_bytes());
+                    h.extend(0u16.to_le_bytes());
+                    h.extend(method.to_le_bytes());
+                    h.extend([0; 8]);
+                    h.extend((data.len() as u32).to_le_bytes());
+                    h.extend((data.len() as u32).to_le_bytes());
+                    h.extend((name.len() as u16).to_le_bytes());
+                    h.extend(0u16.to_le_bytes());
+                    if central {
+                        h.extend([0; 10]);
+                        h.extend(offset.to_le_bytes());
+                    }
+                    h.extend(name.as_bytes());
+                    h
+                };
+                bytes.extend(header(0x0403_4b50, false));
+                bytes.extend(data);
+                directory.extend(header(0x0201_4b50, true));
+            }
+
+            let start = bytes.len() as u32;
+            let count = entries.len() as u16;
+            bytes.extend(&directory);
+            bytes.extend(0x0605_4b50u32.to_le_bytes());
+            bytes.extend([0; 4]);
+            bytes.extend(count.to_le_bytes());
+            bytes.extend(count.to_le_bytes());
+            bytes.extend((directory.len() as u32).to_le_bytes());
+            bytes.extend(start.to_le_bytes());
+            bytes.extend([0; 2]);
+            bytes
+        }
+
+        let entries = [
+            (
+                "arr_0.npy",
+                npy_bytes("(2, 3)", &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]),
+            ),
+            ("arr_1.npy", npy_bytes("(2,)", &[-1.0, -2.0])),
+            ("arr_2.npy", npy_bytes("(1, 2)", &[7.0, 8.0])),
+            ("arr_3.npy", npy_bytes("(1,)", &[9.0])),
+        ];
+        let arrays = npy::parse_npz(&npz(&entries, 0)).unwrap();
+        let names: Vec<&str> = arrays.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["arr_0", "arr_1", "arr_2", "arr_3"]);
+        assert_eq!(arrays[2].1.shape, [1, 2]);
+
+        let path = std::env::temp_dir().join(format!("rustygrad-{}-mlp.npz", std::process::id()));
+        let mut nodes: Vec<Node> = Vec::new();
+        let mlp = Mlp::new(&mut nodes, &[3, 2, 1]);
+
+        fs::write(&path, npz(&entries, 0)).unwrap();
+        mlp.load_npz(&mut nodes, &path).unwrap();
+        let values: Vec<f64> = mlp.parameters().iter().map(|&p| nodes[p].value).collect();
+        assert_eq!(
+            values,
+            [1.0, 2.0, 3.0, -1.0, 4.0, 5.0, 6.0, -2.0, 7.0, 8.0, 9.0]
+        );
+
+        fs::write(&path, npz(&entries[..3], 0)).unwrap();
+        let error = mlp.load_npz(&mut nodes, &path).unwrap_err();
+        assert_eq!(error.to_string(), "archive holds 3 arrays, 2 layers need 4");
+
+        let swapped = [
+            entries[2].clone(),
+            entries[3].clone(),
+            entries[0].clone(),
+            entries[1].clone(),
+        ];
+        fs::write(&path, npz(&swapped, 0)).unwrap();
+        let error = mlp.load_npz(&mut nodes, &path).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "layer 0: weights have shape [1, 2], layer expects [2, 3]"
+        );
+
+        let error = npy::parse_npz(&npz(&entries, 8)).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "npz entry 'arr_0.npy' is compressed; save it with numpy.savez"
+        );
+        assert!(npy::parse_npz(b"not a zip archive at all, but long enough").is_err());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn no_grad_skips_recording_parents() {
+        use crate::Graph;
+
+        let mut graph = Graph::new();
+        let w = graph.leaf(2.0);
+        let x = graph.leaf(3.0);
+
+        let prediction = graph.no_grad(|g| {
+            let y = g.mul(w, x);
+            g.relu(y)
+        });
+        assert_eq!(graph.value(prediction), 6.0);
+        assert!(graph.map().is_empty());
+        assert_eq!(graph.stats().leaf_count, graph.len());
+
+        graph.backward(prediction);
+        assert_eq!(graph.gradient(w), 0.0);
+
+        // Changing an input does not reach the detached nodes.
+        graph.set_value(w, 5.0);
+        graph.forward();
+        assert_eq!(graph.value(prediction), 6.0);
+    }
+
+    #[test]
+    fn no_grad_restores_the_graph_after_a_panic() {
+        use crate::Graph;
+        use std::panic::{self, AssertUnwindSafe};
+
+        let mut graph = Graph::new();
+        let x = graph.leaf(3.0);
+        let y = graph.mul(x, x);
+
+        let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+            graph.no_grad(|g| {
+                g.add(y, x);
+                panic!("evaluation failed");
+            })
+        }));
+        assert!(outcome.is_err());
+        assert_eq!(graph.map().len(), 1);
+
+        graph.backward(y);
+        assert_eq!(graph.gradient(x), 6.0);
+    }
+
+    #[test]
+    fn no_grad_refuses_to_renumber_the_graph() {
+        use crate::Graph;
+        use std::panic::{self, AssertUnwindSafe};
+
+        let mut graph = Graph::new();
+        let x = graph.leaf(3.0);
+        let unused = graph.leaf(5.0);
+        let y = graph.mul(x, x);
+        let len = graph.len();
+
+        graph.no_grad(|g| {
+            let z = g.add(y, unused);
+            let pruned = panic::catch_unwind(AssertUnwindSafe(|| g.prune(&[z])));
+            assert!(pruned.is_err());
+            let optimized = panic::catch_unwind(AssertUnwindSafe(|| g.optimize(&[z])));
+            assert!(optimized.is_err());
+            let dropped = panic::catch_unwind(AssertUnwindSafe(|| g.reset_to(len - 1)));
+            assert!(dropped.is_err());
+
+            // Nested passes keep the outer floor; dropping only their own
+            // nodes is fine.
+            g.no_grad(|g| {
+                assert!(panic::catch_unwind(AssertUnwindSafe(|| g.reset_to(len - 1))).is_err());
+                g.reset_to(len);
+            });
+        });
+        assert_eq!(graph.len(), len);
+        assert_eq!(graph.map().len(), 1);
+
+        graph.backward(y);
+        assert_eq!(graph.gradient(x), 6.0);
+
+        let ids = graph.prune(&[y]);
+        assert_eq!(ids[unused], None);
+    }
+
+    #[test]
+    fn second_derivative_through_symbolic_gradient() {
+        use crate::Graph;
+
+        // f(x) = x^3 + sin(x): f' = 3x^2 + cos(x), f'' = 6x - sin(x).
+        let mut graph = Graph::new();
+        let x = graph.leaf(2.0);
+        let square = graph.mul(x, x);
+        let cube = graph.mul(square, x);
+        let sine = graph.sin(x);
+        let f = graph.add(cube, sine);
+
+        let df = graph.grad(f, &[x]).unwrap()[0];
+        assert!((graph.value(df) - (12.0 + 2.0f64.cos())).abs() < 1e-12);
+
+        graph.backward(df);
+        assert!((graph.gradient(x) - (12.0 - 2.0f64.sin())).abs() < 1e-12);
+        assert_eq!(graph.gradient(f), 0.0);
+    }
+
+    #[test]
+    fn symbolic_gradient_of_every_builtin_op() {
+        use crate::node::Ste;
+        use crate::Graph;
+
+        type Build = fn(&mut Graph, usize, usize) -> usize;
+        let cases: [(&str, Build); 20] = [
+            ("squared_error", |g, x, _| g.squared_error(x, 0.5)),
+            ("logsumexp", |g, x, y| g.logsumexp(&[x, y])),
+            ("softmax", |g, x, y| g.softmax(&[x, y]).unwrap()[0]),
+            ("log_softmax", |g, x, y| g.log_softmax(&[x, y]).unwrap()[1]),
+            ("leaky_relu", |g, x, _| g.leaky_relu(x, 0.1)),
+            ("elu", |g, x, _| g.elu(x, 1.5)),
+            ("gelu", |g, x, _| g.gelu(x)),
+            ("mish", |g, x, _| g.mish(x)),
+            ("abs", |g, x, _| g.abs(x)),
+            ("clamp", |g, x, _| g.clamp(x, -1.0, 1.0)),
+            ("max", |g, x, y| g.max(x, y)),
+            ("min", |g, x, y| g.min(x, y)),
+            ("select", |g, x, y| {
+                let product = g.mul(x, y);
+                g.select(x, product, y)
+            }),
+            ("prod", |g, x, y| g.prod(&[x, y, x])),
+            ("gaussian_kl", |g, x, y| g.gaussian_kl(&[x], &[y])),
+            ("ln_eps", |g, x, _| g.ln_eps(x, 0.1)),
+            ("sign", |g, x, _| g.sign(x, Ste::Clipped)),
+            ("round", |g, x, _| g.round(x, Ste::Identity)),
+            ("threshold", |g, x, _| {
+                g.hard_threshold(x, 0.2, Ste::Clipped)
+            }),
+            ("prelu", |g, x, y| g.prelu(x, y)),
+        ];
+
+        for (name, build) in cases {
+            for (a, b) in [(0.7, -0.4), (-0.6, 0.3)] {
+                let mut graph = Graph::new();
+                let x = graph.leaf(a);
+                let y = graph.leaf(b);
+                let f = build(&mut graph, x, y);
+
+                let dx = graph.grad(f, &[x, y]).unwrap();
+                graph.backward(f);
+                for (input, d) in [x, y].into_iter().zip(dx.iter().copied()) {
+                    let expected = graph.gradient(input);
+                    assert!(
+                        (graph.value(d) - expected).abs() < 1e-12,
+                        "{} at ({}, {})",
+                        name,
+                        a,
+                        b
+                    );
+                }
+
+                // The gradient nodes are differentiable in turn.
+                assert!(
+                    graph.gradcheck(dx[0], &[x, y], 1e-6, 1e-4).is_empty(),
+                    "second derivative of {} at ({}, {})",
+                    name,
+                    a,
+                    b
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn symbolic_gradient_rejects_custom_ops() {
+        use crate::{Graph, GraphError};
+
+        let mut graph = Graph::new();
+        let x = graph.leaf(2.0);
+        let y = graph.custom_unary(x, |v| v * v, |v, grad| 2.0 * v * grad);
+        let f = graph.sin(y);
+        let len = graph.len();
+
+        assert_eq!(graph.grad(f, &[x]), Err(GraphError::Unsupported(y)));
+        assert_eq!(graph.len(), len);
+
+        // Nothing below the custom op needs a gradient, so it is not in the way.
+        graph.set_requires_grad(x, false);
+        assert!(graph.grad(f, &[y]).is_ok());
+    }
+
+    #[test]
+    fn forward_mode_matches_reverse_mode() {
+        use crate::node::dual::Dual;
+        use crate::Graph;
+
+        // Two outputs of one input: y0 = x * exp(x), y1 = tanh(x).
+        let mut graph = Graph::new();
+        let x = graph.leaf(0.5);
+        let e = graph.exp(x);
+        let y0 = graph.mul(x, e);
+        let y1 = graph.tanh(x);
+
+        let duals = graph.jvp(&[(x, 1.0)], &[y0, y1]);
+        assert_eq!(duals[0].value, graph.value(y0));
+        assert!((duals[0].tangent - 1.5 * 0.5f64.exp()).abs() < 1e-12);
+
+        graph.backward(y1);
+        assert_eq!(
+            duals[1],
+            Dual {
+                value: graph.value(y1),
+                tangent: graph.gradient(x)
+            }
+        );
+    }
+
+    #[test]
+    fn jacobian_of_two_outputs() {
+        use crate::Graph;
+
+        // (x * y, x + y) at (2, 3).
+        let mut graph = Graph::new();
+        let x = graph.leaf(2.0);
+        let y = graph.leaf(3.0);
+        let product = graph.mul(x, y);
+        let total = graph.add(x, y);
+
+        graph.backward(total);
+        let jacobian = graph.jacobian(&[product, total], &[x, y]);
+        assert_eq!(jacobian, vec![vec![3.0, 2.0], vec![1.0, 1.0]]);
+        assert_eq!(graph.gradient(x), 1.0);
+    }
+
+    #[test]
+    fn hessian_vector_product() {
+        use crate::Graph;
+
+        // f = x^2 * y has Hessian [[2y, 2x], [2x, 0]]; at (1, 3) times (1, 2)
+        // that is (10, 2).
+        let mut graph = Graph::new();
+        let x = graph.leaf(1.0);
+        let y = graph.leaf(3.0);
+        let square = graph.mul(x, x);
+        let f = graph.mul(square, y);
+        let len = graph.len();
+
+        assert_eq!(graph.hvp(f, &[x, y], &[1.0, 2.0]).unwrap(), vec![10.0, 2.0]);
+        assert_eq!(graph.len(), len);
+        assert_eq!(graph.gradient(x), 0.0);
+    }
+
+    #[test]
+    fn hessian_vector_product_of_losses() {
+        use crate::Graph;
+
+        // (w * x - 1)^2 has Hessian [[2x^2, 2(2wx - 1)], [2(2wx - 1), 2w^2]],
+        // [[8, 10], [10, 4.5]] at (1.5, 2).
+        let mut graph = Graph::new();
+        let w = graph.leaf(1.5);
+        let x = graph.leaf(2.0);
+        let prediction = graph.mul(w, x);
+        let loss = graph.squared_error(prediction, 1.0);
+        assert_eq!(
+            graph.hvp(loss, &[w, x], &[1.0, -1.0]).unwrap(),
+            vec![-2.0, 5.5]
+        );
+
+        // logsumexp and softmax cross-entropy share the Hessian diag(p) - p p^T.
+        let (a, b) = (0.3f64, -0.2f64);
+        let p = a.exp() / (a.exp() + b.exp());
+        let curvature = p * (1.0 - p);
+        let v = [1.0, 2.0];
+        let expected = [curvature * (v[0] - v[1]), curvature * (v[1] - v[0])];
+
+        let losses: [fn(&mut Graph, usize, usize) -> usize; 2] = [
+            |g, x, y| g.logsumexp(&[x, y]),
+            |g, x, y| {
+                let probabilities = g.softmax(&[x, y]).unwrap();
+                let log = g.ln(probabilities[0]);
+                g.neg(log)
+            },
+        ];
+        for build in losses {
+            let mut graph = Graph::new();
+            let x = graph.leaf(a);
+            let y = graph.leaf(b);
+            let loss = build(&mut graph, x, y);
+
+            let product = graph.hvp(loss, &[x, y], &v).unwrap();
+            for (got, want) in product.iter().zip(expected) {
+                assert!((got - want).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn repeated_backward_and_retain_graph() {
+        use crate::{Graph, GraphError};
+
+        let mut graph = Graph::new();
+        let x = graph.leaf(3.0);
+        let square = graph.mul(x, x);
+        let y = graph.mul(square, x);
+
+        // Intermediate gradients start over on every pass, so leaves
+        // accumulate exactly one gradient per pass.
+        graph.backward_retain(y, true).unwrap();
+        graph.backward_retain(y, false).unwrap();
+        assert_eq!(graph.gradient(x), 2.0 * 27.0);
+        assert_eq!(graph.gradient(square), 3.0);
+
+        assert_eq!(graph.backward_retain(y, true), Err(GraphError::Freed(y)));
+        let z = graph.add(square, x);
+        assert_eq!(
+            graph.backward_retain(z, true),
+            Err(GraphError::Freed(square))
+        );
+    }
+
+    #[test]
+    fn serial_and_parallel_backward_agree_across_passes() {
+        use crate::node::scalar::Scalar;
+        use crate::Graph;
+
+        let build = || {
+            let mut graph = Graph::new();
+            let x = graph.leaf(3.0);
+            let square = graph.mul(x, x);
+            let y = graph.mul(square, x);
+            (graph, x, square, y)
+        };
+
+        let (mut serial, x, square, y) = build();
+        serial.backward(y);
+        serial.backward(y);
+
+        let (mut parallel, ..) = build();
+        parallel.backward_parallel(y, 2);
+        parallel.backward_parallel(y, 2);
+
+        for graph in [&serial, &parallel] {
+            assert_eq!(graph.gradient(x), 54.0);
+            assert_eq!(graph.gradient(square), 3.0);
+        }
+
+        let x = Scalar::new(3.0);
+        let square = &x * &x;
+        let y = &square * &x;
+        y.backward();
+        y.backward();
+        assert_eq!(x.gradient(), 54.0);
+        assert_eq!(square.gradient(), 3.0);
+    }
+
+    // `chains` independent tanh chains of `depth` ops each, all reading one
+    // shared leaf, summed into `total`.
+    #[cfg(test)]
+    fn wide_graph(chains: usize, depth: usize) -> (crate::Graph, Vec<usize>, usize) {
+        let mut graph = crate::Graph::new();
+        let shared = graph.leaf(0.5);
+        let mut leaves = vec![shared];
+
+        let mut heads = Vec::new();
+        for i in 0..chains {
+            let leaf = graph.leaf(i as f64 / chains as f64);
+            leaves.push(leaf);
+            let mut head = graph.mul(leaf, shared);
+            for _ in 0..depth {
+                head = graph.tanh(head);
+            }
+            heads.push(head);
+        }
+        let total = graph.sum(&heads);
+        (graph, leaves, total)
+    }
+
+    #[test]
+    fn parallel_backward_matches_serial_on_wide_graphs() {
+        let (mut serial, leaves, total) = wide_graph(600, 8);
+        serial.backward(total);
+
+        for threads in [1, 2, 3, 8] {
+            let (mut parallel, ..) = wide_graph(600, 8);
+            parallel.backward_parallel(total, threads);
+            for &leaf in &leaves {
+                let expected = serial.gradient(leaf);
+                assert!(
+                    (parallel.gradient(leaf) - expected).abs() <= 1e-12 * expected.abs().max(1.0)
+                );
+            }
+        }
+    }
+
+    // Timing, so only meaningful in a release build on a machine with spare
+    // cores: `cargo test --release -- --ignored parallel_backward_is_faster`.
+    #[test]
+    #[ignore]
+    fn parallel_backward_is_faster_on_wide_graphs() {
+        use std::time::{Duration, Instant};
+
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get().min(4));
+        if threads < 2 {
+            return;
+        }
+
+        let time = |f: &mut dyn FnMut()| {
+            (0..5)
+                .map(|_| {
+                    let start = Instant::now();
+                    f();
+                    start.elapsed()
+                })
+                .min()
+                .unwrap_or(Duration::ZERO)
+        };
+
+        let (mut graph, _, total) = wide_graph(20_000, 20);
+        let serial = time(&mut || graph.backward(total));
+        let parallel = time(&mut || graph.backward_parallel(total, threads));
+        assert!(
+            parallel < serial,
+            "{:?} parallel vs {:?} serial",
+            parallel,
+            serial
+        );
+
+        // A chain has no wide level, so the pass stays on the calling thread.
+        let (mut graph, _, total) = wide_graph(1, 2000);
+        let serial = time(&mut || graph.backward(total));
+        let parallel = time(&mut || graph.backward_parallel(total, threads));
+        assert!(
+            parallel < serial * 2,
+            "{:?} parallel vs {:?} serial",
+            parallel,
+            serial
+        );
+    }
+
+    #[test]
+    fn every_backward_pass_refuses_freed_nodes() {
+        use crate::{Graph, GraphError};
+        use std::panic::{self, AssertUnwindSafe};
+
+        let mut graph = Graph::new();
+        let x = graph.leaf(3.0);
+        let square = graph.mul(x, x);
+        graph.backward_retain(square, false).unwrap();
+        let y = graph.add(square, x);
+
+        assert_eq!(graph.try_backward(y), Err(GraphError::Freed(square)));
+
+        let passes: [fn(&mut Graph, usize); 3] = [
+            |g, y| g.backward(y),
+            |g, y| g.backward_multi(&[(y, 1.0)]),
+            |g, y| g.backward_parallel(y, 2),
+        ];
+        for pass in passes {
+            let error = panic::catch_unwind(AssertUnwindSafe(|| pass(&mut graph, y))).unwrap_err();
+            let message = error.downcast_ref::<String>().unwrap();
+            assert_eq!(message, &GraphError::Freed(square).to_string());
+        }
+    }
+
+    #[test]
+    fn checkpointed_segment_matches_plain_graph() {
+        use crate::Graph;
+
+        // Ten tanh(w * h) steps, built directly and as one checkpoint.
+        fn steps(graph: &mut Graph, ids: &[usize]) -> Vec<usize> {
+            let (mut h, w) = (ids[0], ids[1]);
+            for _ in 0..10 {
+                let product = graph.mul(w, h);
+                h = graph.tanh(product);
+            }
+            vec![h]
+        }
+
+        let mut plain = Graph::new();
+        let x = plain.leaf(0.7);
+        let w = plain.leaf(1.3);
+        let y = steps(&mut plain, &[x, w])[0];
+        plain.backward(y);
+
+        let mut checkpointed = Graph::new();
+        let cx = checkpointed.leaf(0.7);
+        let cw = checkpointed.leaf(1.3);
+        let cy = checkpointed.checkpoint(&[cx, cw], steps)[0];
+        checkpointed.backward(cy);
+
+        assert_eq!(checkpointed.len(), 3);
+        assert_eq!(checkpointed.value(cy), plain.value(y));
+        assert_eq!(checkpointed.gradient(cx), plain.gradient(x));
+        assert_eq!(checkpointed.gradient(cw), plain.gradient(w));
+    }
+
+    #[test]
+    fn gradcheck_flags_a_wrong_gradient() {
+        use crate::Graph;
+
+        let mut graph = Graph::new();
+        let a = graph.leaf(1.5);
+        let b = graph.leaf(2.5);
+        let power = graph.pow(a, b);
+        let sine = graph.sin(power);
+        // d/dx x^2 is 2x, not x.
+        let wrong = graph.custom_unary(sine, |x| x * x, |x, g| x * g);
+
+        assert!(graph.gradcheck(sine, &[a, b], 1e-6, 1e-6).is_empty());
+
+        let mismatches = graph.gradcheck(wrong, &[a, b], 1e-6, 1e-6);
+        assert_eq!(mismatches.len(), 2);
+        assert_eq!(mismatches[0].parameter, a);
+        assert!((mismatches[0].numeric - 2.0 * mismatches[0].analytic).abs() < 1e-6);
+        assert_eq!(graph.value(a), 1.5);
+    }
+
+    #[test]
+    fn anomaly_detection_reports_the_offending_node() {
+        use crate::node::anomaly;
+        use crate::Graph;
+        use std::panic::{self, AssertUnwindSafe};
+
+        let mut graph = Graph::new();
+        let x = graph.leaf(-1.0);
+        let y = graph.sqrt(x);
+        assert!(graph.value(y).is_nan());
+
+        anomaly::set_enabled(true);
+        let error = panic::catch_unwind(AssertUnwindSafe(|| graph.sqrt(x))).unwrap_err();
+        anomaly::set_enabled(false);
+
+        let message = error.downcast_ref::<String>().unwrap();
+        assert_eq!(
+            message,
+            "anomaly: node 2 (sqrt) with inputs [-1.0] produced NaN"
+        );
+    }
+
+    #[test]
+    fn graph_stats_count_nodes_and_edges() {
+        use crate::Graph;
+
+        let mut graph = Graph::new();
+        let xs: Vec<usize> = (0..3).map(|i| graph.leaf(i as f64)).collect();
+        let square = graph.mul(xs[0], xs[0]);
+        let total = graph.sum(&[square, xs[1], xs[2]]);
+        graph.relu(total);
+
+        let stats = graph.stats();
+        assert_eq!(stats.node_count, 6);
+        assert_eq!(stats.leaf_count, 3);
+        assert_eq!(stats.edge_count, 2 + 3 + 1);
+        assert_eq!(stats.depth, 3);
+        assert_eq!(stats.operator_counts["sum"], 1);
+        assert!(stats.memory_bytes >= 6 * std::mem::size_of::<crate::Node>());
+    }
+
+    #[test]
+    fn reset_to_reuses_the_graph_between_steps() {
+        use crate::Graph;
+
+        let mut graph = Graph::new();
+        let w = graph.leaf(1.0);
+        let parameters = graph.len();
+
+        for _ in 0..5 {
+            let x = graph.leaf(2.0);
+            let prediction = graph.mul(w, x);
+            let loss = graph.squared_error(prediction, 6.0);
+
+            graph.zero_grad();
+            graph.backward(loss);
+            graph.sgd_step(&[w], 0.05);
+            graph.reset_to(parameters);
+        }
+
+        assert_eq!(graph.len(), 1);
+        assert!(graph.map().is_empty());
+        assert!(graph.value(w) > 2.0);
+    }
+
+    #[test]
+    fn prune_drops_abandoned_branches() {
+        use crate::Graph;
+
+        let mut graph = Graph::new();
+        let x = graph.leaf(2.0);
+        let unused = graph.leaf(5.0);
+        let abandoned = graph.mul(x, unused);
+        let square = graph.mul(x, x);
+        graph.exp(abandoned);
+        let y = graph.add(square, x);
+
+        let ids = graph.prune(&[y]);
+        assert_eq!(ids, vec![Some(0), None, None, Some(1), None, Some(2)]);
+        assert_eq!(graph.len(), 3);
+
+        let y = ids[y].unwrap();
+        assert_eq!(graph.value(y), 6.0);
+        graph.backward(y);
+        assert_eq!(graph.gradient(0), 5.0);
+    }
+
+    #[test]
+    fn optimize_folds_constants_and_merges_duplicates() {
+        use crate::Graph;
+
+        // (x * (2 + 3)) + (x * (2 + 3)), built naively.
+        let mut graph = Graph::new();
+        let x = graph.leaf(1.5);
+        let mut terms = Vec::new();
+        for _ in 0..2 {
+            let two = graph.constant(2.0);
+            let three = graph.constant(3.0);
+            let five = graph.add(two, three);
+            terms.push(graph.mul(x, five));
+        }
+        let y = graph.add(terms[0], terms[1]);
+        assert_eq!(graph.len(), 10);
+
+        let ids = graph.optimize(&[y]);
+        assert_eq!(graph.len(), 4);
+        assert_eq!(ids[terms[0]], ids[terms[1]]);
+        assert_eq!(graph.stats().operator_counts["plus"], 1);
+
+        let y = ids[y].unwrap();
+        assert_eq!(graph.value(y), 15.0);
+        graph.backward(y);
+        assert_eq!(graph.gradient(ids[x].unwrap()), 10.0);
+    }
+
+    #[test]
+    fn extract_separates_model_from_loss() {
+        use crate::Graph;
+
+        let mut graph = Graph::new();
+        let raw = graph.leaf(4.0);
+        let x = graph.sqrt(raw);
+        let w = graph.leaf(3.0);
+        let b = graph.leaf(1.0);
+        let product = graph.mul(w, x);
+        let prediction = graph.add(product, b);
+        let loss = graph.squared_error(prediction, 5.0);
+        graph.backward(loss);
+
+        let (mut model, ids) = graph.extract(&[x], &[prediction]);
+        assert_eq!(model.len(), 5);
+        assert_eq!(ids[raw], None);
+        assert_eq!(ids[loss], None);
+
+        let (x, prediction) = (ids[x].unwrap(), ids[prediction].unwrap());
+        assert_eq!(model.value(prediction), 7.0);
+        assert!(model.nodes()[x].requires_grad());
+
+        model.set_value(x, 1.0);
+        model.forward();
+        assert_eq!(model.value(prediction), 4.0);
+    }
+
+    #[test]
+    fn repl_evaluates_expressions() {
+        use crate::repl::Session;
+
+        let mut session = Session::new();
+        assert_eq!(session.run("1 + 2 * 3"), Ok("7".to_string()));
+        assert_eq!(session.run("(1 + 2) * 3"), Ok("9".to_string()));
+        assert_eq!(session.run("8 / 4 / 2 - 1"), Ok("0".to_string()));
+        assert_eq!(session.run("-2 * 3 + 1"), Ok("-5".to_string()));
+        assert_eq!(session.run("2 - --3"), Ok("-1".to_string()));
+        assert_eq!(session.run("relu(-1) + exp(0)"), Ok("1".to_string()));
+
+        assert_eq!(session.run("1e-3 * 1000"), Ok("1".to_string()));
+        assert_eq!(session.run("2.5E+2 + 1e2"), Ok("350".to_string()));
+        assert!(session.run("2e").is_err());
+
+        assert_eq!(session.run("x = 3"), Ok("x = 3".to_string()));
+        assert_eq!(session.run("y = x * 2"), Ok("y = 6".to_string()));
+        assert_eq!(
+            session.run("x * y + x"),
+            Ok("21\n  d/dx = 7\n  d/dy = 3".to_string())
+        );
+        assert_eq!(session.run("x = x + 1"), Ok("x = 4".to_string()));
+        assert_eq!(session.run("x * x"), Ok("16\n  d/dx = 8".to_string()));
+    }
+
+    #[test]
+    fn repl_reports_errors() {
+        use crate::repl::Session;
+
+        let mut session = Session::new();
+        assert_eq!(session.run("(1 + 2"), Err("expected ')'".to_string()));
+        assert_eq!(
+            session.run("1 + 2)"),
+            Err("unexpected Symbol(')')".to_string())
+        );
+        assert_eq!(
+            session.run("sin(1)"),
+            Err("unknown function 'sin'".to_string())
+        );
+        assert_eq!(
+            session.run("1 2"),
+            Err("unexpected Number(2.0)".to_string())
+        );
+        assert_eq!(
+            session.run("x + 1"),
+            Err("undefined variable 'x'".to_string())
+        );
+        assert_eq!(
+            session.run("1 +"),
+            Err("unexpected end of input".to_string())
+        );
+        assert_eq!(
+            session.run("2 % 3"),
+            Err("unexpected character '%'".to_string())
+        );
+    }
+}
@@ -1,10 +1,12 @@
-// #![allow(dead_code, unused_variables)]
-
-mod node;
-
+use rustygrad::node;
 use std::collections::HashMap;
 
 fn main() {
+    if std::env::args().nth(1).as_deref() == Some("repl") {
+        rustygrad::repl::run().unwrap();
+        return;
+    }
+
     let mut map: node::Map = HashMap::new();
     let mut nodes: Vec<node::Node> = Vec::new();
 
@@ -17,9 +19,7 @@ fn main() {
     let layer1 = node::Layer::new(&mut nodes, 2, 1);
     let final_layer = layer1.connect(&mut map, &mut nodes, inputs);
 
-    nodes[final_layer[0]].gradient = 1.0;
-
-    node::backwards(&mut map, &mut nodes);
+    node::backwards(&mut map, &mut nodes, final_layer[0]);
 
     println!("{:?}", map);
     println!("{:?}", map);
@@ -27,10 +27,3 @@ fn main() {
         println!("{:?}", n);
     }
 }
-
-mod test {
-    #[test]
-    fn basics() {
-        // TODO
-    }
-}
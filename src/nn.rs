@@ -0,0 +1,377 @@
+use crate::float::Float;
+use crate::graph::Graph;
+use crate::node::{self, vecops, Activation, Layer, Map, Node};
+use crate::npy;
+use crate::rng::{self, RngBackend};
+use std::io::{self, Read};
+use std::path::Path;
+
+/// A building block of a network: something that maps input nodes to output
+/// nodes in a `Graph` and owns zero or more trainable parameters.
+pub trait Module {
+    fn forward(&self, graph: &mut Graph, inputs: &[usize]) -> Vec<usize>;
+
+    /// The trainable leaf nodes of this module, in a stable order.
+    fn parameters(&self) -> Vec<usize> {
+        Vec::new()
+    }
+
+    /// `parameters()` paired with a dotted name for each. Modules without
+    /// their own naming scheme number their parameters from zero.
+    fn named_parameters(&self) -> Vec<(String, usize)> {
+        self.parameters()
+            .into_iter()
+            .enumerate()
+            .map(|(i, id)| (i.to_string(), id))
+            .collect()
+    }
+
+    /// Number of inputs `forward` expects, if the module has a fixed one.
+    fn input_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// Number of outputs `forward` produces, if the module has a fixed one.
+    fn output_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// Looks a parameter up by the name `named_parameters` gives it.
+    fn parameter(&self, name: &str) -> Option<usize> {
+        self.named_parameters()
+            .into_iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, id)| id)
+    }
+}
+
+impl Module for Layer {
+    fn forward(&self, graph: &mut Graph, inputs: &[usize]) -> Vec<usize> {
+        let (map, nodes) = graph.parts_mut();
+        self.connect(map, nodes, inputs.to_vec())
+    }
+
+    fn parameters(&self) -> Vec<usize> {
+        Layer::parameters(self)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, usize)> {
+        Layer::named_parameters(self)
+    }
+
+    fn input_size(&self) -> Option<usize> {
+        Some(self.count_in())
+    }
+
+    fn output_size(&self) -> Option<usize> {
+        Some(self.count_out())
+    }
+}
+
+/// Applies `relu` to every input.
+pub struct Relu;
+
+impl Module for Relu {
+    fn forward(&self, graph: &mut Graph, inputs: &[usize]) -> Vec<usize> {
+        let (map, nodes) = graph.parts_mut();
+        vecops::relu(map, nodes, inputs)
+    }
+}
+
+/// Applies `tanh` to every input.
+pub struct Tanh;
+
+impl Module for Tanh {
+    fn forward(&self, graph: &mut Graph, inputs: &[usize]) -> Vec<usize> {
+        let (map, nodes) = graph.parts_mut();
+        vecops::tanh(map, nodes, inputs)
+    }
+}
+
+/// Applies `sigmoid` to every input.
+pub struct Sigmoid;
+
+impl Module for Sigmoid {
+    fn forward(&self, graph: &mut Graph, inputs: &[usize]) -> Vec<usize> {
+        let (map, nodes) = graph.parts_mut();
+        vecops::sigmoid(map, nodes, inputs)
+    }
+}
+
+/// `prelu` with a single learnable slope shared by every input.
+pub struct PRelu {
+    slope: usize,
+}
+
+impl PRelu {
+    /// Adds the slope to `nodes` as a leaf starting at `initial_slope`
+    /// (0.25 is the usual choice).
+    pub fn new<T: Float>(nodes: &mut Vec<Node<T>>, initial_slope: T) -> Self {
+        PRelu {
+            slope: node::new_node(nodes, initial_slope),
+        }
+    }
+
+    pub fn slope(&self) -> usize {
+        self.slope
+    }
+}
+
+impl Module for PRelu {
+    fn forward(&self, graph: &mut Graph, inputs: &[usize]) -> Vec<usize> {
+        inputs.iter().map(|&x| graph.prelu(x, self.slope)).collect()
+    }
+
+    fn parameters(&self) -> Vec<usize> {
+        vec![self.slope]
+    }
+
+    fn named_parameters(&self) -> Vec<(String, usize)> {
+        vec![("slope".to_string(), self.slope)]
+    }
+}
+
+/// Applies `exp` to every input.
+pub struct Exp;
+
+impl Module for Exp {
+    fn forward(&self, graph: &mut Graph, inputs: &[usize]) -> Vec<usize> {
+        let (map, nodes) = graph.parts_mut();
+        vecops::exp(map, nodes, inputs)
+    }
+}
+
+/// Runs its modules one after another, feeding each the outputs of the
+/// previous one.
+pub struct Sequential {
+    modules: Vec<Box<dyn Module>>,
+}
+
+impl Sequential {
+    /// Panics if a module's output size does not match the next module's
+    /// input size, where both are known.
+    pub fn new(modules: Vec<Box<dyn Module>>) -> Self {
+        let mut size = None;
+
+        for (i, module) in modules.iter().enumerate() {
+            if let (Some(produced), Some(expected)) = (size, module.input_size()) {
+                assert!(
+                    produced == expected,
+                    "module {} expects {} inputs, but the previous module produces {}",
+                    i,
+                    expected,
+                    produced
+                );
+            }
+            size = module.output_size().or(size);
+        }
+
+        Sequential { modules }
+    }
+
+    pub fn len(&self) -> usize {
+        self.modules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.modules.is_empty()
+    }
+}
+
+impl Module for Sequential {
+    fn forward(&self, graph: &mut Graph, inputs: &[usize]) -> Vec<usize> {
+        if let Some(expected) = self.input_size() {
+            assert!(inputs.len() == expected);
+        }
+
+        self.modules
+            .iter()
+            .fold(inputs.to_vec(), |x, module| module.forward(graph, &x))
+    }
+
+    fn parameters(&self) -> Vec<usize> {
+        self.modules.iter().flat_map(|m| m.parameters()).collect()
+    }
+
+    /// Each module's names prefixed with its position, e.g.
+    /// `layer1.neuron0.weight2`.
+    fn named_parameters(&self) -> Vec<(String, usize)> {
+        self.modules
+            .iter()
+            .enumerate()
+            .flat_map(|(i, module)| {
+                module
+                    .named_parameters()
+                    .into_iter()
+                    .map(move |(name, id)| (format!("layer{}.{}", i, name), id))
+            })
+            .collect()
+    }
+
+    fn input_size(&self) -> Option<usize> {
+        self.modules.iter().find_map(|m| m.input_size())
+    }
+
+    fn output_size(&self) -> Option<usize> {
+        self.modules.iter().rev().find_map(|m| m.output_size())
+    }
+}
+
+/// A multi-layer perceptron, micrograd's `MLP([2, 16, 16, 1])`: fully connected
+/// layers of the given sizes with relu between them and a linear output layer.
+pub struct Mlp {
+    layers: Vec<Layer>,
+}
+
+impl Mlp {
+    /// `sizes` lists the input size followed by each layer's output size.
+    pub fn new<T: Float>(nodes: &mut Vec<Node<T>>, sizes: &[u64]) -> Self {
+        Mlp::new_with_rng(nodes, sizes, &mut rng::default_rng())
+    }
+
+    pub fn new_with_rng<T: Float>(
+        nodes: &mut Vec<Node<T>>,
+        sizes: &[u64],
+        rng: &mut impl RngBackend,
+    ) -> Self {
+        assert!(sizes.len() >= 2, "an MLP needs an input and an output size");
+
+        let last = sizes.len() - 2;
+        let layers = sizes
+            .windows(2)
+            .enumerate()
+            .map(|(i, pair)| {
+                let layer = Layer::new_with_rng(nodes, pair[0], pair[1], rng);
+                if i == last {
+                    layer.with_activation(Activation::Linear)
+                } else {
+                    layer
+                }
+            })
+            .collect();
+
+        Mlp { layers }
+    }
+
+    pub fn layers(&self) -> &[Layer] {
+        &self.layers
+    }
+
+    pub fn connect<T: Float>(
+        &self,
+        map: &mut Map,
+        nodes: &mut Vec<Node<T>>,
+        x: Vec<usize>,
+    ) -> Vec<usize> {
+        self.layers
+            .iter()
+            .fold(x, |h, layer| layer.connect(map, nodes, h))
+    }
+
+    /// Evaluates the network on plain values without adding anything to the
+    /// graph.
+    pub fn predict<T: Float>(&self, nodes: &[Node<T>], x: &[T]) -> Vec<T> {
+        self.layers
+            .iter()
+            .fold(x.to_vec(), |h, layer| layer.predict(nodes, &h))
+    }
+
+    pub fn parameters(&self) -> Vec<usize> {
+        self.layers.iter().flat_map(|l| l.parameters()).collect()
+    }
+
+    /// Overwrites every parameter from a flat buffer in `parameters()` order:
+    /// each layer's values in turn, laid out as `Layer::load_weights` expects.
+    /// Fails, leaving the parameters alone, unless there is exactly one value
+    /// per parameter.
+    pub fn load_weights<T: Float>(&self, nodes: &mut [Node<T>], values: &[T]) -> io::Result<()> {
+        let count = self.parameters().len();
+        if values.len() != count {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "got {} values, network has {} parameters",
+                    values.len(),
+                    count
+                ),
+            ));
+        }
+
+        let mut rest = values;
+        for layer in &self.layers {
+            let (head, tail) = rest.split_at(layer.parameters().len());
+            layer.load_weights(nodes, head)?;
+            rest = tail;
+        }
+        Ok(())
+    }
+
+    /// Reads whitespace-separated numbers from `reader` and loads them with
+    /// `load_weights`.
+    pub fn init_from<T: Float, R: Read>(&self, nodes: &mut [Node<T>], reader: R) -> io::Result<()> {
+        self.load_weights(nodes, &node::read_values(reader)?)
+    }
+
+    /// Loads an archive written by `numpy.savez` holding a weight and a bias
+    /// array for each layer, in order, each laid out as `Layer::load_npy`
+    /// expects. Saving a PyTorch stack of `nn.Linear` layers with
+    /// `numpy.savez(path, *(p.detach().numpy() for p in model.parameters()))`
+    /// gives that order.
+    pub fn load_npz<T: Float, P: AsRef<Path>>(
+        &self,
+        nodes: &mut [Node<T>],
+        path: P,
+    ) -> io::Result<()> {
+        let arrays = npy::read_npz(path)?;
+        if arrays.len() != 2 * self.layers.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "archive holds {} arrays, {} layers need {}",
+                    arrays.len(),
+                    self.layers.len(),
+                    2 * self.layers.len()
+                ),
+            ));
+        }
+
+        for (i, (layer, pair)) in self.layers.iter().zip(arrays.chunks(2)).enumerate() {
+            layer
+                .load_arrays(nodes, &pair[0].1, &pair[1].1)
+                .map_err(|e| io::Error::new(e.kind(), format!("layer {}: {}", i, e)))?;
+        }
+        Ok(())
+    }
+}
+
+impl Module for Mlp {
+    fn forward(&self, graph: &mut Graph, inputs: &[usize]) -> Vec<usize> {
+        let (map, nodes) = graph.parts_mut();
+        self.connect(map, nodes, inputs.to_vec())
+    }
+
+    fn parameters(&self) -> Vec<usize> {
+        Mlp::parameters(self)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, usize)> {
+        self.layers
+            .iter()
+            .enumerate()
+            .flat_map(|(i, layer)| {
+                layer
+                    .named_parameters()
+                    .into_iter()
+                    .map(move |(name, id)| (format!("layer{}.{}", i, name), id))
+            })
+            .collect()
+    }
+
+    fn input_size(&self) -> Option<usize> {
+        self.layers.first().map(|l| l.count_in())
+    }
+
+    fn output_size(&self) -> Option<usize> {
+        self.layers.last().map(|l| l.count_out())
+    }
+}
@@ -1,154 +1,1281 @@
-use rand::Rng;
+pub mod anomaly;
+#[cfg(feature = "ndarray")]
+pub mod array;
+pub mod checkpoint;
+pub mod dual;
+pub mod export;
+pub mod gradcheck;
+pub mod hooks;
+#[cfg(feature = "nalgebra")]
+pub mod linalg;
+pub mod optimize;
+pub mod parallel;
+pub mod scalar;
+pub mod stats;
+pub mod symbolic;
+pub mod vecops;
+
+use crate::error::GraphError;
+use crate::float::Float;
+use crate::npy;
+use crate::rng::{self, RngBackend};
+use hooks::Hooks;
+use smallvec::{smallvec, SmallVec};
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::collections::VecDeque;
-use std::sync::atomic::AtomicUsize;
-use std::sync::atomic::Ordering;
-
-static OBJECT_COUNTER: AtomicUsize = AtomicUsize::new(0);
+use std::fmt;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::Arc;
+/// Ids of a node's inputs, in operand order.
+pub type Children = SmallVec<[usize; 2]>;
+pub type Map = HashMap<usize, Children>;
 
-pub type Map = HashMap<usize, [Option<usize>; 2]>;
+/// Replacement backward rule for a single node: given the node (its value and
+/// accumulated gradient) and the values of its children, returns the gradient
+/// to add to each child, in order.
+pub type BackwardFn<T = f64> = Box<dyn Fn(&Node<T>, &[T]) -> Vec<T>>;
+pub type Overrides<T = f64> = HashMap<usize, BackwardFn<T>>;
 
 #[derive(Debug, Clone)]
-enum Operator {
+enum Operator<T> {
     Plus,
+    Sub,
     Mul,
+    Div,
     Pow,
     Relu,
+    Cos,
+    Sin,
+    Clamp(T, T),
+    Abs,
+    Sqrt,
+    Mish,
+    Elu(T),
+    Gelu,
+    LeakyRelu(T),
+    Prelu,
+    Select,
+    Max,
+    Min,
+    Sigmoid,
+    Tanh,
+    Neg,
+    Identity,
+    Sum,
+    Mean,
+    Prod,
+    Exp,
+    Ln(Option<T>),
+    SquaredError(T),
+    GaussianKl,
+    LogSumExp,
+    Gt,
+    Lt,
+    Ge,
+    Le,
+    Eq,
+    Sign(Ste),
+    Round(Ste),
+    Threshold(T, Ste),
+    Custom(Arc<dyn CustomOp<T>>),
+}
+
+/// A differentiable operation defined outside the crate, built into the graph
+/// with `custom`. `backwards`, `forward` and `eval` dispatch to it like any
+/// built-in operator.
+pub trait CustomOp<T = f64>: fmt::Debug + Send + Sync {
+    /// Short label used by `stats` and the DOT export.
+    fn name(&self) -> &'static str;
+
+    fn forward(&self, inputs: &[T]) -> T;
+
+    /// Gradient contributions to each input, in order, given the node's value
+    /// and accumulated gradient and the values of its inputs.
+    fn backward(&self, value: T, gradient: T, inputs: &[T]) -> Vec<T>;
+}
+
+/// Straight-through estimator used as the backward pass of hard ops, whose
+/// true derivative is zero almost everywhere.
+#[derive(Debug, Clone, Copy)]
+pub enum Ste {
+    /// No estimator: use the true derivative and block the gradient.
+    None,
+    /// Pass the gradient through unchanged.
+    Identity,
+    /// Pass the gradient through only where `|x| <= 1`.
+    Clipped,
+}
+
+impl Ste {
+    fn passes<T: Float>(&self, x: T) -> bool {
+        match self {
+            Ste::None => false,
+            Ste::Identity => true,
+            Ste::Clipped => x.abs() <= T::one(),
+        }
+    }
+}
+
+const GELU_SCALE: f64 = 0.7978845608028654; // sqrt(2 / pi)
+const GELU_CUBIC: f64 = 0.044715;
+
+// The argument of tanh in the GELU approximation.
+fn gelu_inner<T: Float>(x: T) -> T {
+    T::from_f64(GELU_SCALE) * (x + T::from_f64(GELU_CUBIC) * x * x * x)
+}
+
+// `ln(1 + exp(x))` without overflow for large `x`.
+fn softplus<T: Float>(x: T) -> T {
+    x.max(T::zero()) + (-x.abs()).exp().ln_1p()
+}
+
+impl<T: Float> Operator<T> {
+    fn forward(&self, inputs: &[T]) -> T {
+        let zero = T::zero();
+        // Only custom ops can have no inputs, and they do not read `a`.
+        let a = inputs.first().copied().unwrap_or(zero);
+        let one = T::one();
+        let half = T::from_f64(0.5);
+        let mask = |holds: bool| if holds { one } else { zero };
+
+        match self {
+            Operator::Plus => a + inputs[1],
+            Operator::Sub => a - inputs[1],
+            Operator::Mul => a * inputs[1],
+            Operator::Div => a / inputs[1],
+            Operator::Pow => a.powf(inputs[1]),
+            Operator::Relu => a.max(zero),
+            Operator::Cos => a.cos(),
+            Operator::Sin => a.sin(),
+            Operator::Clamp(lo, hi) => a.max(*lo).min(*hi),
+            Operator::Abs => a.abs(),
+            Operator::Sqrt => a.sqrt(),
+            Operator::Mish => a * softplus(a).tanh(),
+            Operator::Elu(alpha) => {
+                if a > zero {
+                    a
+                } else {
+                    *alpha * (a.exp() - one)
+                }
+            }
+            Operator::Gelu => half * a * (one + gelu_inner(a).tanh()),
+            Operator::LeakyRelu(alpha) => {
+                if a > zero {
+                    a
+                } else {
+                    *alpha * a
+                }
+            }
+            Operator::Prelu => {
+                if a > zero {
+                    a
+                } else {
+                    inputs[1] * a
+                }
+            }
+            Operator::Select => {
+                if a > zero {
+                    inputs[1]
+                } else {
+                    inputs[2]
+                }
+            }
+            Operator::Max => a.max(inputs[1]),
+            Operator::Min => a.min(inputs[1]),
+            Operator::Sigmoid => {
+                if a >= zero {
+                    one / (one + (-a).exp())
+                } else {
+                    a.exp() / (one + a.exp())
+                }
+            }
+            Operator::Tanh => a.tanh(),
+            Operator::Neg => -a,
+            Operator::Identity => a,
+            Operator::Sum => inputs.iter().sum(),
+            Operator::Prod => inputs.iter().fold(one, |total, &x| total * x),
+            Operator::Mean => inputs.iter().sum::<T>() / T::from_f64(inputs.len() as f64),
+            Operator::Exp => a.exp(),
+            Operator::Ln(None) => a.ln(),
+            Operator::Ln(Some(eps)) => a.max(*eps).ln(),
+            Operator::SquaredError(target) => (a - *target).powi(2),
+            Operator::GaussianKl => {
+                let log_var = inputs[1];
+                half * (log_var.exp() + a * a - one - log_var)
+            }
+            Operator::LogSumExp => {
+                let max = inputs.iter().fold(T::neg_infinity(), |m, &x| m.max(x));
+                if !max.is_finite() {
+                    return max;
+                }
+                max + inputs.iter().map(|&x| (x - max).exp()).sum::<T>().ln()
+            }
+            Operator::Gt => mask(a > inputs[1]),
+            Operator::Lt => mask(a < inputs[1]),
+            Operator::Ge => mask(a >= inputs[1]),
+            Operator::Le => mask(a <= inputs[1]),
+            Operator::Eq => mask(a == inputs[1]),
+            Operator::Sign(_) => {
+                if a > zero {
+                    one
+                } else if a < zero {
+                    -one
+                } else {
+                    zero
+                }
+            }
+            Operator::Round(_) => a.round(),
+            Operator::Threshold(threshold, _) => mask(a > *threshold),
+            Operator::Custom(op) => op.forward(inputs),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Operator::Plus => "plus",
+            Operator::Sub => "sub",
+            Operator::Mul => "mul",
+            Operator::Div => "div",
+            Operator::Pow => "pow",
+            Operator::Relu => "relu",
+            Operator::Cos => "cos",
+            Operator::Sin => "sin",
+            Operator::Clamp(..) => "clamp",
+            Operator::Abs => "abs",
+            Operator::Sqrt => "sqrt",
+            Operator::Mish => "mish",
+            Operator::Elu(..) => "elu",
+            Operator::Gelu => "gelu",
+            Operator::LeakyRelu(..) => "leaky_relu",
+            Operator::Prelu => "prelu",
+            Operator::Select => "select",
+            Operator::Max => "max",
+            Operator::Min => "min",
+            Operator::Sigmoid => "sigmoid",
+            Operator::Tanh => "tanh",
+            Operator::Neg => "neg",
+            Operator::Identity => "identity",
+            Operator::Sum => "sum",
+            Operator::Mean => "mean",
+            Operator::Prod => "prod",
+            Operator::Exp => "exp",
+            Operator::Ln(_) => "ln",
+            Operator::SquaredError(_) => "squared_error",
+            Operator::GaussianKl => "gaussian_kl",
+            Operator::LogSumExp => "logsumexp",
+            Operator::Gt => "gt",
+            Operator::Lt => "lt",
+            Operator::Ge => "ge",
+            Operator::Le => "le",
+            Operator::Eq => "eq",
+            Operator::Sign(_) => "sign",
+            Operator::Round(_) => "round",
+            Operator::Threshold(..) => "threshold",
+            Operator::Custom(op) => op.name(),
+        }
+    }
+
+    /// Gradient contributions to each of the node's inputs, given its own
+    /// value and accumulated gradient and the values of its inputs.
+    fn backward(&self, value: T, gradient: T, inputs: &[T]) -> SmallVec<[T; 2]> {
+        let zero = T::zero();
+        // Only custom ops can have no inputs, and they do not read `a`.
+        let a = inputs.first().copied().unwrap_or(zero);
+        let one = T::one();
+        let half = T::from_f64(0.5);
+        let pass = |passes: bool| if passes { gradient } else { zero };
+
+        match self {
+            Operator::Plus => smallvec![gradient, gradient],
+            Operator::Sub => smallvec![gradient, -gradient],
+            Operator::Mul => smallvec![inputs[1] * gradient, a * gradient],
+            Operator::Div => {
+                let b = inputs[1];
+                smallvec![gradient / b, -a / (b * b) * gradient]
+            }
+            // The exponent's gradient is taken as zero where `ln(a)` is
+            // undefined, as it is for integer powers of a negative base.
+            Operator::Pow => {
+                let b = inputs[1];
+                let exponent = if a > zero { value * a.ln() } else { zero };
+                smallvec![b * a.powf(b - one) * gradient, exponent * gradient]
+            }
+            Operator::GaussianKl => {
+                smallvec![a * gradient, half * (inputs[1].exp() - one) * gradient]
+            }
+            Operator::LogSumExp => inputs
+                .iter()
+                .map(|&x| (x - value).exp() * gradient)
+                .collect(),
+            Operator::Relu => smallvec![pass(value > zero)],
+            Operator::Cos => smallvec![-a.sin() * gradient],
+            Operator::Sin => smallvec![a.cos() * gradient],
+            Operator::Clamp(lo, hi) => smallvec![pass(*lo <= a && a <= *hi)],
+            Operator::Abs => {
+                let sign = if a > zero {
+                    one
+                } else if a < zero {
+                    -one
+                } else {
+                    zero
+                };
+                smallvec![sign * gradient]
+            }
+            Operator::Sqrt => smallvec![half / value * gradient],
+            Operator::Mish => {
+                let t = softplus(a).tanh();
+                let sigmoid = Operator::Sigmoid.forward(&[a]);
+                smallvec![(t + a * (one - t * t) * sigmoid) * gradient]
+            }
+            Operator::Elu(alpha) => {
+                let slope = if a > zero { one } else { value + *alpha };
+                smallvec![slope * gradient]
+            }
+            Operator::Gelu => {
+                let t = gelu_inner(a).tanh();
+                let du = T::from_f64(GELU_SCALE) * (one + T::from_f64(3.0 * GELU_CUBIC) * a * a);
+                smallvec![(half * (one + t) + half * a * (one - t * t) * du) * gradient]
+            }
+            Operator::LeakyRelu(alpha) => {
+                let slope = if a > zero { one } else { *alpha };
+                smallvec![slope * gradient]
+            }
+            Operator::Prelu => {
+                if a > zero {
+                    smallvec![gradient, zero]
+                } else {
+                    smallvec![inputs[1] * gradient, a * gradient]
+                }
+            }
+            Operator::Select if a > zero => smallvec![zero, gradient, zero],
+            Operator::Select => smallvec![zero, zero, gradient],
+            // Ties send the whole gradient to the first operand.
+            Operator::Max if a >= inputs[1] => smallvec![gradient, zero],
+            Operator::Min if a <= inputs[1] => smallvec![gradient, zero],
+            Operator::Max | Operator::Min => smallvec![zero, gradient],
+            Operator::Sigmoid => smallvec![value * (one - value) * gradient],
+            Operator::Tanh => smallvec![(one - value * value) * gradient],
+            Operator::Neg => smallvec![-gradient],
+            Operator::Identity => smallvec![gradient],
+            Operator::Sum => inputs.iter().map(|_| gradient).collect(),
+            // `value / x_i` written as the product of the other inputs, so an
+            // input of zero still gets its gradient.
+            Operator::Prod => {
+                let mut before = one;
+                let mut gradients: SmallVec<[T; 2]> = inputs
+                    .iter()
+                    .map(|&x| {
+                        let partial = before;
+                        before *= x;
+                        partial
+                    })
+                    .collect();
+
+                let mut after = gradient;
+                for (partial, &x) in gradients.iter_mut().zip(inputs).rev() {
+                    *partial *= after;
+                    after *= x;
+                }
+
+                gradients
+            }
+            Operator::Mean => {
+                let share = gradient / T::from_f64(inputs.len() as f64);
+                inputs.iter().map(|_| share).collect()
+            }
+            Operator::Exp => smallvec![value * gradient],
+            Operator::Ln(None) => smallvec![gradient / a],
+            Operator::Ln(Some(eps)) => smallvec![if a > *eps { gradient / a } else { zero }],
+            Operator::SquaredError(target) => {
+                smallvec![T::from_f64(2.0) * (a - *target) * gradient]
+            }
+            Operator::Sign(ste) | Operator::Round(ste) => smallvec![pass(ste.passes(a))],
+            Operator::Threshold(threshold, ste) => smallvec![pass(ste.passes(a - *threshold))],
+            Operator::Gt | Operator::Lt | Operator::Ge | Operator::Le | Operator::Eq => {
+                smallvec![zero; inputs.len()]
+            }
+            Operator::Custom(op) => op.backward(value, gradient, inputs).into_iter().collect(),
+        }
+    }
+
+    // Comparison results are 0/1 masks, so no gradient flows back through them.
+    fn stops_gradient(&self) -> bool {
+        matches!(
+            self,
+            Operator::Gt | Operator::Lt | Operator::Ge | Operator::Le | Operator::Eq
+        )
+    }
 }
 
 #[derive(Debug, Clone)]
-pub struct Node {
+pub struct Node<T = f64> {
     id: usize,
-    value: f64,
-    pub gradient: f64,
-    operator: Option<Operator>,
+    pub value: T,
+    pub gradient: T,
+    operator: Option<Operator<T>>,
+    dirty: bool,
+    requires_grad: bool,
 }
 
-impl Node {
-    fn new(value: f64, operator: Option<Operator>) -> Self {
+impl<T: Float> Node<T> {
+    fn new(id: usize, value: T, operator: Option<Operator<T>>) -> Self {
         Node {
-            id: OBJECT_COUNTER.fetch_add(1, Ordering::SeqCst),
-            value: value,
-            gradient: 0.0,
-            operator: operator,
+            id,
+            value,
+            gradient: T::zero(),
+            operator,
+            dirty: false,
+            requires_grad: true,
         }
     }
+
+    /// Whether `backwards` accumulates into this node; see `set_requires_grad`.
+    pub fn requires_grad(&self) -> bool {
+        self.requires_grad
+    }
+}
+
+pub fn new_node<T: Float>(nodes: &mut Vec<Node<T>>, value: T) -> usize {
+    append_node(nodes, value, None)
 }
 
-pub fn new_node(nodes: &mut Vec<Node>, value: f64) -> usize {
-    nodes.push(Node::new(value, None));
-    let node_id = nodes.last_mut().unwrap().id;
+/// A leaf that `backwards` and `sgd_step` leave alone, for the constants of an
+/// expression.
+pub fn constant<T: Float>(nodes: &mut Vec<Node<T>>, value: T) -> usize {
+    let node_id = new_node(nodes, value);
+    nodes[node_id].requires_grad = false;
     node_id
 }
 
-fn append_node(nodes: &mut Vec<Node>, value: f64, operator: Option<Operator>) -> usize {
-    nodes.push(Node::new(value, operator));
-    let node_id = nodes.last_mut().unwrap().id;
+// Stores the inputs of a freshly built node.
+fn record<T: Float>(map: &mut Map, nodes: &[Node<T>], node_id: usize, children: Children) {
+    map.insert(node_id, children);
+    anomaly::check_value(map, nodes, node_id);
+}
+
+// Ids are positions in the arena, so each `Vec<Node>` numbers its own nodes
+// from zero and several graphs can coexist.
+fn append_node<T: Float>(
+    nodes: &mut Vec<Node<T>>,
+    value: T,
+    operator: Option<Operator<T>>,
+) -> usize {
+    let node_id = nodes.len();
+    nodes.push(Node::new(node_id, value, operator));
     node_id
 }
 
-pub fn add(
+pub fn add<T: Float>(
     map: &mut Map,
-    nodes: &mut Vec<Node>,
+    nodes: &mut Vec<Node<T>>,
     index_self: usize,
     index_other: usize,
-) -> (usize, f64) {
+) -> (usize, T) {
     let a = nodes.get(index_self).unwrap();
     let b = nodes.get(index_other).unwrap();
 
     let value = a.value + b.value;
     let node_id = append_node(nodes, value, Some(Operator::Plus));
-    map.insert(node_id, [Some(index_self), Some(index_other)]);
+    record(map, nodes, node_id, smallvec![index_self, index_other]);
 
     (node_id, value)
 }
 
-fn mul(
+pub fn mul<T: Float>(
     map: &mut Map,
-    nodes: &mut Vec<Node>,
+    nodes: &mut Vec<Node<T>>,
     index_self: usize,
     index_other: usize,
-) -> (usize, f64) {
+) -> (usize, T) {
     let a = nodes.get(index_self).unwrap();
     let b = nodes.get(index_other).unwrap();
 
     let value = a.value * b.value;
     let node_id = append_node(nodes, value, Some(Operator::Mul));
 
-    map.insert(node_id, [Some(index_self), Some(index_other)]);
+    record(map, nodes, node_id, smallvec![index_self, index_other]);
 
     (node_id, value)
 }
 
-fn pow(
+pub fn sub<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    index_self: usize,
+    index_other: usize,
+) -> (usize, T) {
+    binary(map, nodes, index_self, index_other, Operator::Sub)
+}
+
+pub fn div<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    index_self: usize,
+    index_other: usize,
+) -> (usize, T) {
+    binary(map, nodes, index_self, index_other, Operator::Div)
+}
+
+pub fn pow<T: Float>(
     map: &mut Map,
-    nodes: &mut Vec<Node>,
+    nodes: &mut Vec<Node<T>>,
     index_self: usize,
     index_other: usize,
-) -> (usize, f64) {
+) -> (usize, T) {
     let a = nodes.get(index_self).unwrap();
     let b = nodes.get(index_other).unwrap();
 
     let value = a.value.powf(b.value);
     let node_id = append_node(nodes, value, Some(Operator::Pow));
 
-    map.insert(node_id, [Some(index_self), Some(index_other)]);
+    record(map, nodes, node_id, smallvec![index_self, index_other]);
 
     (node_id, value)
 }
 
-fn relu(map: &mut Map, nodes: &mut Vec<Node>, index_self: usize) -> (usize, f64) {
-    let a = nodes.get(index_self).unwrap();
+pub fn relu<T: Float>(map: &mut Map, nodes: &mut Vec<Node<T>>, index_self: usize) -> (usize, T) {
+    unary(map, nodes, index_self, Operator::Relu)
+}
+
+fn binary<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    index_self: usize,
+    index_other: usize,
+    operator: Operator<T>,
+) -> (usize, T) {
+    let a = nodes.get(index_self).unwrap().value;
+    let b = nodes.get(index_other).unwrap().value;
+
+    let value = operator.forward(&[a, b]);
+    let node_id = append_node(nodes, value, Some(operator));
 
-    let mut value = 0.0;
-    if a.value > 0.0 {
-        value = a.value
+    record(map, nodes, node_id, smallvec![index_self, index_other]);
+
+    (node_id, value)
+}
+
+pub fn gt<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    index_self: usize,
+    index_other: usize,
+) -> (usize, T) {
+    binary(map, nodes, index_self, index_other, Operator::Gt)
+}
+
+pub fn lt<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    index_self: usize,
+    index_other: usize,
+) -> (usize, T) {
+    binary(map, nodes, index_self, index_other, Operator::Lt)
+}
+
+pub fn ge<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    index_self: usize,
+    index_other: usize,
+) -> (usize, T) {
+    binary(map, nodes, index_self, index_other, Operator::Ge)
+}
+
+pub fn le<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    index_self: usize,
+    index_other: usize,
+) -> (usize, T) {
+    binary(map, nodes, index_self, index_other, Operator::Le)
+}
+
+pub fn eq<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    index_self: usize,
+    index_other: usize,
+) -> (usize, T) {
+    binary(map, nodes, index_self, index_other, Operator::Eq)
+}
+
+/// `a` where `condition` is positive and `b` elsewhere, chosen again on every
+/// `forward`. Both `a` and `b` are in the graph, but the backward pass only
+/// goes through the one taken; `condition` itself gets no gradient.
+pub fn select<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    condition: usize,
+    a: usize,
+    b: usize,
+) -> (usize, T) {
+    let inputs = [nodes[condition].value, nodes[a].value, nodes[b].value];
+
+    let value = Operator::Select.forward(&inputs);
+    let node_id = append_node(nodes, value, Some(Operator::Select));
+
+    record(map, nodes, node_id, smallvec![condition, a, b]);
+
+    (node_id, value)
+}
+
+/// Builds both branches and joins them with `select` on `predicate` (taken
+/// when its value is positive, e.g. a mask from `gt`). Every `forward` picks
+/// the branch again from the current predicate, and `backwards` only walks
+/// the branch taken, so the other one gets no gradient even where its own
+/// derivative is NaN. Both branches are still evaluated on every pass.
+pub fn cond<T: Float, Then, Else>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    predicate: usize,
+    then_builder: Then,
+    else_builder: Else,
+) -> usize
+where
+    Then: FnOnce(&mut Map, &mut Vec<Node<T>>) -> usize,
+    Else: FnOnce(&mut Map, &mut Vec<Node<T>>) -> usize,
+{
+    let then_branch = then_builder(map, nodes);
+    let else_branch = else_builder(map, nodes);
+    select(map, nodes, predicate, then_branch, else_branch).0
+}
+
+// The inputs a backward pass walks into from `node_id`: all of them, except
+// that a `select` only leads into the branch it currently takes.
+pub(crate) fn followed<'a, T: Float>(
+    map: &'a Map,
+    nodes: &[Node<T>],
+    node_id: usize,
+) -> &'a [usize] {
+    let Some(children) = map.get(&node_id) else {
+        return &[];
+    };
+    match nodes[node_id].operator {
+        Some(Operator::Select) if nodes[children[0]].value > T::zero() => &children[1..2],
+        Some(Operator::Select) => &children[2..3],
+        _ => children,
+    }
+}
+
+/// Unrolls `step_fn` for `n_steps` iterations, feeding each step the state
+/// produced by the previous one, and returns the state after every step.
+///
+/// Every iteration is recorded in the graph, so `backwards` from any of the
+/// returned states propagates through all earlier steps (BPTT).
+pub fn scan<T: Float, F>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    n_steps: usize,
+    state_init: Vec<usize>,
+    mut step_fn: F,
+) -> Vec<Vec<usize>>
+where
+    F: FnMut(&mut Map, &mut Vec<Node<T>>, usize, &[usize]) -> Vec<usize>,
+{
+    unroll(
+        &mut (map, nodes),
+        n_steps,
+        state_init,
+        |(map, nodes), step, state| step_fn(map, nodes, step, state),
+    )
+}
+
+// The loop behind `scan` and `Graph::scan`, building into `context`.
+pub(crate) fn unroll<C, F>(
+    context: &mut C,
+    n_steps: usize,
+    state_init: Vec<usize>,
+    mut step_fn: F,
+) -> Vec<Vec<usize>>
+where
+    F: FnMut(&mut C, usize, &[usize]) -> Vec<usize>,
+{
+    let mut states = Vec::with_capacity(n_steps);
+    let mut state = state_init;
+
+    for step in 0..n_steps {
+        state = step_fn(context, step, &state);
+        states.push(state.clone());
     }
 
-    let node_id = append_node(nodes, value, Some(Operator::Relu));
+    states
+}
 
-    map.insert(node_id, [Some(index_self), None]);
+fn unary<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    index_self: usize,
+    operator: Operator<T>,
+) -> (usize, T) {
+    let value = operator.forward(&[nodes.get(index_self).unwrap().value]);
+    let node_id = append_node(nodes, value, Some(operator));
+
+    record(map, nodes, node_id, smallvec![index_self]);
 
     (node_id, value)
 }
 
+// A single node over all of `xs`, for reductions whose backward is cheaper
+// done at once than through a chain of binary nodes. Callers give empty
+// `xs` their own meaning first.
+fn nary<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    xs: &[usize],
+    operator: Operator<T>,
+) -> usize {
+    let inputs: Vec<T> = xs.iter().map(|&x| nodes[x].value).collect();
+    let value = operator.forward(&inputs);
+    let node_id = append_node(nodes, value, Some(operator));
+
+    record(map, nodes, node_id, xs.iter().copied().collect());
+
+    node_id
+}
+
+pub fn tanh<T: Float>(map: &mut Map, nodes: &mut Vec<Node<T>>, index_self: usize) -> (usize, T) {
+    unary(map, nodes, index_self, Operator::Tanh)
+}
+
+/// `1 / (1 + exp(-x))`, evaluated so that neither branch overflows.
+pub fn sigmoid<T: Float>(map: &mut Map, nodes: &mut Vec<Node<T>>, index_self: usize) -> (usize, T) {
+    unary(map, nodes, index_self, Operator::Sigmoid)
+}
+
+/// `x` for positive inputs and `alpha * x` otherwise, so negative inputs still
+/// receive a gradient scaled by `alpha`.
+pub fn leaky_relu<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    index_self: usize,
+    alpha: T,
+) -> (usize, T) {
+    unary(map, nodes, index_self, Operator::LeakyRelu(alpha))
+}
+
+/// GELU with the tanh approximation,
+/// `0.5 * x * (1 + tanh(sqrt(2 / pi) * (x + 0.044715 * x^3)))`.
+pub fn gelu<T: Float>(map: &mut Map, nodes: &mut Vec<Node<T>>, index_self: usize) -> (usize, T) {
+    unary(map, nodes, index_self, Operator::Gelu)
+}
+
+/// `x` for positive inputs and `alpha * (exp(x) - 1)` otherwise.
+pub fn elu<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    index_self: usize,
+    alpha: T,
+) -> (usize, T) {
+    unary(map, nodes, index_self, Operator::Elu(alpha))
+}
+
+/// `x * tanh(softplus(x))`, fused into a single node.
+pub fn mish<T: Float>(map: &mut Map, nodes: &mut Vec<Node<T>>, index_self: usize) -> (usize, T) {
+    unary(map, nodes, index_self, Operator::Mish)
+}
+
+/// Leaky relu whose negative slope is the node `slope`, so the slope is
+/// learned along with the other parameters.
+pub fn prelu<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    index_self: usize,
+    slope: usize,
+) -> (usize, T) {
+    binary(map, nodes, index_self, slope, Operator::Prelu)
+}
+
+/// Square root. Negative inputs give NaN; `Graph::try_sqrt` rejects them.
+pub fn sqrt<T: Float>(map: &mut Map, nodes: &mut Vec<Node<T>>, index_self: usize) -> (usize, T) {
+    unary(map, nodes, index_self, Operator::Sqrt)
+}
+
+/// `|x|`, with the subgradient `sign(x)` (zero at zero).
+pub fn abs<T: Float>(map: &mut Map, nodes: &mut Vec<Node<T>>, index_self: usize) -> (usize, T) {
+    unary(map, nodes, index_self, Operator::Abs)
+}
+
+/// The larger of two nodes; the gradient flows only to the one selected.
+pub fn max<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    index_self: usize,
+    index_other: usize,
+) -> (usize, T) {
+    binary(map, nodes, index_self, index_other, Operator::Max)
+}
+
+/// The smaller of two nodes; the gradient flows only to the one selected.
+pub fn min<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    index_self: usize,
+    index_other: usize,
+) -> (usize, T) {
+    binary(map, nodes, index_self, index_other, Operator::Min)
+}
+
+/// Limits `x` to `[lo, hi]`. The gradient passes only while `x` is inside the
+/// interval (boundaries included).
+pub fn clamp<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    index_self: usize,
+    lo: T,
+    hi: T,
+) -> (usize, T) {
+    assert!(lo <= hi, "clamp needs lo <= hi");
+    unary(map, nodes, index_self, Operator::Clamp(lo, hi))
+}
+
+pub fn sin<T: Float>(map: &mut Map, nodes: &mut Vec<Node<T>>, index_self: usize) -> (usize, T) {
+    unary(map, nodes, index_self, Operator::Sin)
+}
+
+pub fn cos<T: Float>(map: &mut Map, nodes: &mut Vec<Node<T>>, index_self: usize) -> (usize, T) {
+    unary(map, nodes, index_self, Operator::Cos)
+}
+
+pub fn add_scalar<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    index_self: usize,
+    scalar: T,
+) -> (usize, T) {
+    let other = constant(nodes, scalar);
+    add(map, nodes, index_self, other)
+}
+
+pub fn mul_scalar<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    index_self: usize,
+    scalar: T,
+) -> (usize, T) {
+    let other = constant(nodes, scalar);
+    mul(map, nodes, index_self, other)
+}
+
+pub fn pow_scalar<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    index_self: usize,
+    exponent: T,
+) -> (usize, T) {
+    let other = constant(nodes, exponent);
+    pow(map, nodes, index_self, other)
+}
+
+pub fn neg<T: Float>(map: &mut Map, nodes: &mut Vec<Node<T>>, index_self: usize) -> (usize, T) {
+    unary(map, nodes, index_self, Operator::Neg)
+}
+
+pub fn sign<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    index_self: usize,
+    ste: Ste,
+) -> (usize, T) {
+    unary(map, nodes, index_self, Operator::Sign(ste))
+}
+
+pub fn round<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    index_self: usize,
+    ste: Ste,
+) -> (usize, T) {
+    unary(map, nodes, index_self, Operator::Round(ste))
+}
+
+/// Outputs 1.0 where the input exceeds `threshold` and 0.0 elsewhere. A clipped
+/// estimator passes the gradient within 1.0 of the threshold.
+pub fn hard_threshold<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    index_self: usize,
+    threshold: T,
+    ste: Ste,
+) -> (usize, T) {
+    unary(map, nodes, index_self, Operator::Threshold(threshold, ste))
+}
+
+pub fn exp<T: Float>(map: &mut Map, nodes: &mut Vec<Node<T>>, index_self: usize) -> (usize, T) {
+    unary(map, nodes, index_self, Operator::Exp)
+}
+
+/// Applies a user-defined operator to `inputs`, in order. `inputs` may be
+/// empty, for an op that produces its value from nothing but itself.
+pub fn custom<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    op: Arc<dyn CustomOp<T>>,
+    inputs: &[usize],
+) -> (usize, T) {
+    let values: SmallVec<[T; 2]> = inputs.iter().map(|&i| nodes[i].value).collect();
+
+    let operator = Operator::Custom(op);
+    let value = operator.forward(&values);
+    let node_id = append_node(nodes, value, Some(operator));
+
+    record(map, nodes, node_id, inputs.iter().copied().collect());
+
+    (node_id, value)
+}
+
+/// Natural logarithm. Non-positive inputs give `-inf` or NaN; use `ln_eps`
+/// or `Graph::try_ln` when the input may not be positive.
+pub fn ln<T: Float>(map: &mut Map, nodes: &mut Vec<Node<T>>, index_self: usize) -> (usize, T) {
+    unary(map, nodes, index_self, Operator::Ln(None))
+}
+
+/// `ln(max(x, eps))`, which stays finite for any input. Below `eps` the value
+/// is constant, so no gradient flows back.
+pub fn ln_eps<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    index_self: usize,
+    eps: T,
+) -> (usize, T) {
+    assert!(eps > T::zero(), "eps must be positive");
+    unary(map, nodes, index_self, Operator::Ln(Some(eps)))
+}
+
+/// A `CustomOp` from a pair of closures; see `custom_unary`.
+struct Unary<F, B> {
+    forward: F,
+    backward: B,
+}
+
+impl<F, B> fmt::Debug for Unary<F, B> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Unary")
+    }
+}
+
+impl<T, F, B> CustomOp<T> for Unary<F, B>
+where
+    T: Float,
+    F: Fn(T) -> T + Send + Sync,
+    B: Fn(T, T) -> T + Send + Sync,
+{
+    fn name(&self) -> &'static str {
+        "custom_unary"
+    }
+
+    fn forward(&self, inputs: &[T]) -> T {
+        (self.forward)(inputs[0])
+    }
+
+    fn backward(&self, _value: T, gradient: T, inputs: &[T]) -> Vec<T> {
+        vec![(self.backward)(inputs[0], gradient)]
+    }
+}
+
+/// A one-off differentiable function of a single node: `forward(x)` gives the
+/// value and `backward(x, gradient)` the gradient to pass on to `x`.
+pub fn custom_unary<T, F, B>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    index_self: usize,
+    forward: F,
+    backward: B,
+) -> (usize, T)
+where
+    T: Float,
+    F: Fn(T) -> T + Send + Sync + 'static,
+    B: Fn(T, T) -> T + Send + Sync + 'static,
+{
+    custom(
+        map,
+        nodes,
+        Arc::new(Unary { forward, backward }),
+        &[index_self],
+    )
+}
+
+/// Draws `mu + exp(0.5 * log_var) * eps` with `eps ~ N(0, 1)` taken from `rng`.
+/// `eps` and the factor 0.5 are constants, so of the leaves only `mu` and
+/// `log_var` get gradients.
+pub fn sample_normal<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    mu: usize,
+    log_var: usize,
+    rng: &mut impl RngBackend,
+) -> (usize, T) {
+    let half = constant(nodes, T::from_f64(0.5));
+    let (half_log_var, _) = mul(map, nodes, log_var, half);
+    let (std_dev, _) = exp(map, nodes, half_log_var);
+
+    let eps = constant(nodes, T::from_f64(rng.normal()));
+    let (noise, _) = mul(map, nodes, std_dev, eps);
+
+    add(map, nodes, mu, noise)
+}
+
+/// Squared distance between a node and a constant target, `(x - target)^2`.
+pub fn squared_error<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    index_self: usize,
+    target: T,
+) -> (usize, T) {
+    unary(map, nodes, index_self, Operator::SquaredError(target))
+}
+
+fn gaussian_kl_term<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    mu: usize,
+    log_var: usize,
+) -> usize {
+    let m = nodes.get(mu).unwrap().value;
+    let lv = nodes.get(log_var).unwrap().value;
+
+    let value = Operator::GaussianKl.forward(&[m, lv]);
+    let node_id = append_node(nodes, value, Some(Operator::GaussianKl));
+
+    record(map, nodes, node_id, smallvec![mu, log_var]);
+
+    node_id
+}
+
+/// Closed-form `KL(N(mu, exp(log_var)) || N(0, 1))` summed over dimensions,
+/// `0.5 * sum(exp(log_var) + mu^2 - 1 - log_var)`. With no dimensions it is
+/// a zero constant. Panics if `mu` and `log_var` differ in length.
+pub fn gaussian_kl<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    mu: &[usize],
+    log_var: &[usize],
+) -> usize {
+    assert_eq!(mu.len(), log_var.len(), "mu and log_var differ in length");
+    if mu.is_empty() {
+        return constant(nodes, T::zero());
+    }
+
+    let mut total = gaussian_kl_term(map, nodes, mu[0], log_var[0]);
+    for i in 1..mu.len() {
+        let term = gaussian_kl_term(map, nodes, mu[i], log_var[i]);
+        total = add(map, nodes, total, term).0;
+    }
+
+    total
+}
+
+/// Selects the `k` largest of `xs`, returning a node for each (largest first)
+/// together with its position in `xs`. Gradients only reach the selected
+/// inputs.
+pub fn top_k<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    xs: &[usize],
+    k: usize,
+) -> (Vec<usize>, Vec<usize>) {
+    let mut positions: Vec<usize> = (0..xs.len()).collect();
+    positions.sort_by(|&i, &j| nodes[xs[j]].value.total_cmp(&nodes[xs[i]].value));
+    positions.truncate(k);
+
+    let selected = positions
+        .iter()
+        .map(|&i| unary(map, nodes, xs[i], Operator::Identity).0)
+        .collect();
+
+    (selected, positions)
+}
+
+/// `xs[0] + ... + xs[n - 1]` as one node, which passes its gradient unchanged
+/// to every input. The sum of no inputs is a zero constant.
+pub fn sum<T: Float>(map: &mut Map, nodes: &mut Vec<Node<T>>, xs: &[usize]) -> usize {
+    if xs.is_empty() {
+        return constant(nodes, T::zero());
+    }
+    nary(map, nodes, xs, Operator::Sum)
+}
+
+/// `xs[0] * ws[0] + ... + xs[n - 1] * ws[n - 1]`: one product node per pair,
+/// summed by a single `sum` node.
+pub fn dot<T: Float>(map: &mut Map, nodes: &mut Vec<Node<T>>, xs: &[usize], ws: &[usize]) -> usize {
+    assert_eq!(xs.len(), ws.len());
+
+    let products: Vec<usize> = xs
+        .iter()
+        .zip(ws)
+        .map(|(&x, &w)| mul(map, nodes, x, w).0)
+        .collect();
+
+    sum(map, nodes, &products)
+}
+
+/// `dot(xs, ys) / sqrt(dot(xs, xs) * dot(ys, ys))`, built from graph nodes so
+/// both vectors get gradients. Undefined (NaN) if either vector is all zeros.
+pub fn cosine_similarity<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    xs: &[usize],
+    ys: &[usize],
+) -> usize {
+    let numerator = dot(map, nodes, xs, ys);
+    let xx = dot(map, nodes, xs, xs);
+    let yy = dot(map, nodes, ys, ys);
+
+    let (norms, _) = mul(map, nodes, xx, yy);
+    let (denominator, _) = sqrt(map, nodes, norms);
+    div(map, nodes, numerator, denominator).0
+}
+
+/// The average of `xs` as one node; each input receives `1 / n` of the
+/// gradient, as a batch-averaged loss needs. `GraphError::EmptyInput` if `xs`
+/// is empty, since no value would be meaningful.
+pub fn mean<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    xs: &[usize],
+) -> Result<usize, GraphError> {
+    if xs.is_empty() {
+        return Err(GraphError::EmptyInput);
+    }
+    Ok(nary(map, nodes, xs, Operator::Mean))
+}
+
+/// `xs[0] * ... * xs[n - 1]` as one node. Each input receives the output
+/// gradient times the product of the other inputs. The product of no inputs
+/// is a constant one.
+pub fn prod<T: Float>(map: &mut Map, nodes: &mut Vec<Node<T>>, xs: &[usize]) -> usize {
+    if xs.is_empty() {
+        return constant(nodes, T::one());
+    }
+    nary(map, nodes, xs, Operator::Prod)
+}
+
+/// Running sums of `xs`: output `i` is `xs[0] + ... + xs[i]`, so the gradient
+/// reaching `xs[i]` is the reverse cumulative sum of the output gradients.
+pub fn cumsum<T: Float>(map: &mut Map, nodes: &mut Vec<Node<T>>, xs: &[usize]) -> Vec<usize> {
+    let mut outputs: Vec<usize> = Vec::with_capacity(xs.len());
+
+    for &x in xs {
+        let total = match outputs.last() {
+            Some(&previous) => add(map, nodes, x, previous).0,
+            None => unary(map, nodes, x, Operator::Identity).0,
+        };
+        outputs.push(total);
+    }
+
+    outputs
+}
+
+/// `log(sum(exp(x)))` over `xs` as one node, shifted by the largest input so
+/// no intermediate ever overflows. The gradient reaching each input is its
+/// softmax weight. Over no inputs it is a `-inf` constant, the log of an
+/// empty sum.
+pub fn logsumexp<T: Float>(map: &mut Map, nodes: &mut Vec<Node<T>>, xs: &[usize]) -> usize {
+    if xs.is_empty() {
+        return constant(nodes, T::neg_infinity());
+    }
+    nary(map, nodes, xs, Operator::LogSumExp)
+}
+
+/// `x_i - log(sum(exp(x)))` for each input, one `sub` from a single shared
+/// `logsumexp` node, so the graph grows by `n + 1` nodes and the backward path
+/// through it is two nodes deep. The backward pass works out to
+/// `g_j - softmax_j * sum(g)`, without ever materializing the softmax. Fails
+/// on an empty `xs`.
+pub fn log_softmax<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    xs: &[usize],
+) -> Result<Vec<usize>, GraphError> {
+    if xs.is_empty() {
+        return Err(GraphError::EmptyInput);
+    }
+    let normalizer = logsumexp(map, nodes, xs);
+
+    Ok(xs
+        .iter()
+        .map(|&x| sub(map, nodes, x, normalizer).0)
+        .collect())
+}
+
+/// `exp(x_i) / sum(exp(x))` for each input, built as `exp(log_softmax(xs))`
+/// so it stays stable for large inputs and every output depends on every
+/// input in the graph. Fails on an empty `xs`.
+pub fn softmax<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    xs: &[usize],
+) -> Result<Vec<usize>, GraphError> {
+    Ok(log_softmax(map, nodes, xs)?
+        .into_iter()
+        .map(|x| exp(map, nodes, x).0)
+        .collect())
+}
+
+/// Non-linearity a neuron applies to its weighted sum.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Activation {
+    Relu,
+    Tanh,
+    /// `elu` with the given `alpha`.
+    Elu(f64),
+    /// No non-linearity, e.g. for an output layer producing unbounded scores.
+    Linear,
+}
+
+impl Activation {
+    fn apply<T: Float>(&self, x: T) -> T {
+        match self {
+            Activation::Relu => x.max(T::zero()),
+            Activation::Tanh => x.tanh(),
+            Activation::Elu(alpha) => Operator::Elu(T::from_f64(*alpha)).forward(&[x]),
+            Activation::Linear => x,
+        }
+    }
+
+    fn connect<T: Float>(&self, map: &mut Map, nodes: &mut Vec<Node<T>>, x: usize) -> usize {
+        match self {
+            Activation::Relu => relu(map, nodes, x).0,
+            Activation::Tanh => tanh(map, nodes, x).0,
+            Activation::Elu(alpha) => elu(map, nodes, x, T::from_f64(*alpha)).0,
+            Activation::Linear => x,
+        }
+    }
+}
+
 pub struct Neuron {
     weights: Vec<usize>,
     bias: usize,
+    activation: Activation,
 }
 
 impl Neuron {
-    fn new(nodes: &mut Vec<Node>, count_in: u64) -> Self {
-        let mut rng = rand::thread_rng();
-
+    fn new_with_rng<T: Float>(
+        nodes: &mut Vec<Node<T>>,
+        count_in: u64,
+        rng: &mut impl RngBackend,
+    ) -> Self {
         let mut weights = Vec::new();
-        for i in 0..count_in {
-            weights.push(new_node(nodes, rng.gen_range(-1.0..1.0)))
+        for _ in 0..count_in {
+            weights.push(new_node(nodes, T::from_f64(rng.uniform(-1.0, 1.0))))
         }
 
-        let bias = new_node(nodes, rng.gen_range(-1.0..1.0));
+        let bias = new_node(nodes, T::from_f64(rng.uniform(-1.0, 1.0)));
 
         Neuron {
-            weights: weights,
-            bias: bias,
+            weights,
+            bias,
+            activation: Activation::Relu,
         }
     }
 
-    fn connect(&self, map: &mut Map, x: &Vec<usize>, nodes: &mut Vec<Node>) -> usize {
+    fn connect<T: Float>(&self, map: &mut Map, x: &[usize], nodes: &mut Vec<Node<T>>) -> usize {
         assert!(self.weights.len() == x.len());
 
-        let (last_index, _) = self
-            .weights
-            .iter()
-            .enumerate()
-            .map(|(i, _)| mul(map, nodes, x[i], self.weights[i]))
-            .last()
-            .unwrap();
-
-        let (sum, _) = add(map, nodes, self.bias, last_index);
-        let (res, _) = relu(map, nodes, sum);
-        res
+        let weighted = dot(map, nodes, x, &self.weights);
+        let (sum, _) = add(map, nodes, self.bias, weighted);
+        self.activation.connect(map, nodes, sum)
     }
 
     fn parameters(&self) -> Vec<usize> {
@@ -159,6 +1286,36 @@ impl Neuron {
 
         p
     }
+
+    fn named_parameters(&self) -> Vec<(String, usize)> {
+        let mut p: Vec<(String, usize)> = self
+            .weights
+            .iter()
+            .enumerate()
+            .map(|(i, &w)| (format!("weight{}", i), w))
+            .collect();
+
+        p.push(("bias".to_string(), self.bias));
+
+        p
+    }
+}
+
+// Whitespace-separated numbers from `reader`, for the `init_from` loaders.
+pub(crate) fn read_values<T: Float, R: Read>(mut reader: R) -> io::Result<Vec<T>> {
+    let mut text = String::new();
+    reader.read_to_string(&mut text)?;
+
+    text.split_whitespace()
+        .map(|token| {
+            token.parse::<f64>().map(T::from_f64).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("'{}' is not a number", token),
+                )
+            })
+        })
+        .collect()
 }
 
 pub struct Layer {
@@ -166,23 +1323,199 @@ pub struct Layer {
 }
 
 impl Layer {
-    pub fn new(nodes: &mut Vec<Node>, count_in: u64, count_out: u64) -> Self {
+    pub fn new<T: Float>(nodes: &mut Vec<Node<T>>, count_in: u64, count_out: u64) -> Self {
+        Layer::new_with_rng(nodes, count_in, count_out, &mut rng::default_rng())
+    }
+
+    /// `new_with_rng` with `rng::seeded_rng(seed)`: the same seed always
+    /// produces the same weights.
+    pub fn new_seeded<T: Float>(
+        nodes: &mut Vec<Node<T>>,
+        count_in: u64,
+        count_out: u64,
+        seed: u64,
+    ) -> Self {
+        Layer::new_with_rng(nodes, count_in, count_out, &mut rng::seeded_rng(seed))
+    }
+
+    pub fn new_with_rng<T: Float>(
+        nodes: &mut Vec<Node<T>>,
+        count_in: u64,
+        count_out: u64,
+        rng: &mut impl RngBackend,
+    ) -> Self {
         let mut neurons = Vec::new();
 
         for _ in 0..count_out {
-            neurons.push(Neuron::new(nodes, count_in));
+            neurons.push(Neuron::new_with_rng(nodes, count_in, rng));
         }
 
-        Layer { neurons: neurons }
+        Layer { neurons }
     }
 
-    pub fn connect(&self, map: &mut Map, nodes: &mut Vec<Node>, x: Vec<usize>) -> Vec<usize> {
+    pub fn connect<T: Float>(
+        &self,
+        map: &mut Map,
+        nodes: &mut Vec<Node<T>>,
+        x: Vec<usize>,
+    ) -> Vec<usize> {
         self.neurons
             .iter()
             .map(|neuron| neuron.connect(map, &x, nodes))
             .collect()
     }
 
+    /// Replaces the non-linearity of every neuron; layers start out with
+    /// `Activation::Relu`.
+    pub fn with_activation(mut self, activation: Activation) -> Self {
+        for neuron in self.neurons.iter_mut() {
+            neuron.activation = activation;
+        }
+        self
+    }
+
+    pub fn activation(&self) -> Activation {
+        self.neurons
+            .first()
+            .map_or(Activation::Relu, |n| n.activation)
+    }
+
+    /// Number of inputs each neuron takes.
+    pub fn count_in(&self) -> usize {
+        self.neurons.first().map_or(0, |n| n.weights.len())
+    }
+
+    /// Number of neurons, and so of outputs.
+    pub fn count_out(&self) -> usize {
+        self.neurons.len()
+    }
+
+    /// Overwrites the layer's parameters from a flat buffer in `parameters()`
+    /// order: for each neuron, its weights followed by its bias. Fails, leaving
+    /// the parameters alone, unless there is exactly one value per parameter.
+    pub fn load_weights<T: Float>(&self, nodes: &mut [Node<T>], values: &[T]) -> io::Result<()> {
+        let parameters = self.parameters();
+        if parameters.len() != values.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "got {} values, layer has {} parameters",
+                    values.len(),
+                    parameters.len()
+                ),
+            ));
+        }
+
+        for (&p, &value) in parameters.iter().zip(values) {
+            nodes[p].value = value;
+        }
+        Ok(())
+    }
+
+    /// Reads whitespace-separated numbers from `reader` and loads them with
+    /// `load_weights`.
+    pub fn init_from<T: Float, R: Read>(&self, nodes: &mut [Node<T>], reader: R) -> io::Result<()> {
+        self.load_weights(nodes, &read_values(reader)?)
+    }
+
+    /// Loads weights saved with `numpy.save`, laid out like a PyTorch `nn.Linear`:
+    /// `weights` is `(count_out, count_in)` and `bias` is `(count_out,)`.
+    pub fn load_npy<T: Float, P: AsRef<Path>>(
+        &self,
+        nodes: &mut [Node<T>],
+        weights: P,
+        bias: P,
+    ) -> io::Result<()> {
+        self.load_arrays(nodes, &npy::read(weights)?, &npy::read(bias)?)
+    }
+
+    /// `load_npy` from arrays already read, e.g. by `npy::read_npz`.
+    pub fn load_arrays<T: Float>(
+        &self,
+        nodes: &mut [Node<T>],
+        weights: &npy::Array,
+        bias: &npy::Array,
+    ) -> io::Result<()> {
+        let count_out = self.count_out();
+        let count_in = self.count_in();
+
+        if weights.shape != [count_out, count_in] {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "weights have shape {:?}, layer expects [{}, {}]",
+                    weights.shape, count_out, count_in
+                ),
+            ));
+        }
+        if bias.shape != [count_out] {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "bias has shape {:?}, layer expects [{}]",
+                    bias.shape, count_out
+                ),
+            ));
+        }
+
+        for (i, neuron) in self.neurons.iter().enumerate() {
+            let row = &weights.data[i * count_in..(i + 1) * count_in];
+            for (&w, &value) in neuron.weights.iter().zip(row) {
+                nodes[w].value = T::from_f64(value);
+            }
+            nodes[neuron.bias].value = T::from_f64(bias.data[i]);
+        }
+
+        Ok(())
+    }
+
+    /// Evaluates the layer on plain values without adding anything to the
+    /// graph.
+    pub fn predict<T: Float>(&self, nodes: &[Node<T>], x: &[T]) -> Vec<T> {
+        self.neurons
+            .iter()
+            .map(|neuron| {
+                assert!(neuron.weights.len() == x.len());
+
+                let sum: T = neuron
+                    .weights
+                    .iter()
+                    .zip(x)
+                    .map(|(&w, &xi)| nodes[w].value * xi)
+                    .sum();
+
+                neuron.activation.apply(sum + nodes[neuron.bias].value)
+            })
+            .collect()
+    }
+
+    pub fn predict_batch<T: Float>(&self, nodes: &[Node<T>], inputs: &[Vec<T>]) -> Vec<Vec<T>> {
+        inputs.iter().map(|x| self.predict(nodes, x)).collect()
+    }
+
+    /// Like `predict_batch`, but maps each row of outputs to probabilities: a
+    /// sigmoid for a single output, a softmax otherwise.
+    pub fn predict_proba_batch<T: Float>(
+        &self,
+        nodes: &[Node<T>],
+        inputs: &[Vec<T>],
+    ) -> Vec<Vec<T>> {
+        self.predict_batch(nodes, inputs)
+            .into_iter()
+            .map(|outputs| {
+                if outputs.len() == 1 {
+                    return vec![T::one() / (T::one() + (-outputs[0]).exp())];
+                }
+
+                let max = outputs.iter().cloned().fold(T::neg_infinity(), T::max);
+                let exps: Vec<T> = outputs.iter().map(|&o| (o - max).exp()).collect();
+                let total: T = exps.iter().sum();
+
+                exps.into_iter().map(|e| e / total).collect()
+            })
+            .collect()
+    }
+
     pub fn parameters(&self) -> Vec<usize> {
         let mut p = Vec::new();
 
@@ -192,82 +1525,390 @@ impl Layer {
 
         p
     }
+
+    /// `parameters()` paired with names of the form `neuron0.weight2` and
+    /// `neuron0.bias`.
+    pub fn named_parameters(&self) -> Vec<(String, usize)> {
+        let mut p = Vec::new();
+
+        for (j, neuron) in self.neurons.iter().enumerate() {
+            for (name, id) in neuron.named_parameters() {
+                p.push((format!("neuron{}.{}", j, name), id));
+            }
+        }
+
+        p
+    }
+}
+
+fn recompute<T: Float>(map: &Map, nodes: &mut [Node<T>], node_id: usize, hooks: &Hooks<T>) {
+    if let (Some(operator), Some(children)) = (&nodes[node_id].operator, map.get(&node_id)) {
+        let inputs: SmallVec<[T; 2]> = children.iter().map(|&c| nodes[c].value).collect();
+
+        let value = operator.forward(&inputs);
+        nodes[node_id].value = value;
+        anomaly::check_value(map, nodes, node_id);
+        hooks.run_forward(nodes, node_id);
+    }
 }
 
-fn get_child_nodes(
-    nodes: &mut Vec<Node>,
-    index_a: Option<usize>,
-    index_b: Option<usize>,
-) -> [Option<&mut Node>; 2] {
-    match (index_a, index_b) {
-        (Some(x), Some(y)) => {
-            assert!(y > x);
+/// Changes a leaf's value and marks it so the next `forward` recomputes the
+/// nodes that depend on it. Writing `value` directly skips this tracking.
+pub fn set_value<T: Float>(nodes: &mut [Node<T>], node: usize, value: T) {
+    nodes[node].value = value;
+    nodes[node].dirty = true;
+}
 
-            let (lb, rb) = nodes.split_at_mut(y);
+/// Freezes (`false`) or unfreezes a node. `backwards` adds nothing to a frozen
+/// node's gradient and so never reaches the nodes it was computed from, and
+/// `sgd_step` leaves it unchanged.
+pub fn set_requires_grad<T: Float>(nodes: &mut [Node<T>], node: usize, requires_grad: bool) {
+    nodes[node].requires_grad = requires_grad;
+}
 
-            [Some(&mut lb[x]), Some(&mut rb[0])]
+/// Moves every unfrozen parameter against its gradient by `learning_rate`.
+pub fn sgd_step<T: Float>(nodes: &mut [Node<T>], parameters: &[usize], learning_rate: T) {
+    for &p in parameters {
+        if nodes[p].requires_grad {
+            nodes[p].value -= learning_rate * nodes[p].gradient;
         }
-        (Some(x), None) => [Some(&mut nodes[x]), None],
-        _ => [None, None],
     }
 }
 
-pub fn backwards(map: &mut Map, nodes: &mut Vec<Node>) {
+/// Drops every node with an id of `len` or more, e.g. the activations of a
+/// finished training step, keeping the nodes before it (typically the
+/// parameters) with their values and gradients.
+pub fn truncate<T: Float>(map: &mut Map, nodes: &mut Vec<Node<T>>, len: usize) {
+    nodes.truncate(len);
+    map.retain(|&id, _| id < len);
+}
+
+/// Turns `nodes` into leaves holding their current values, forgetting the
+/// operators that computed them.
+pub fn detach<T: Float>(nodes: &mut [Node<T>]) {
+    for node in nodes {
+        node.operator = None;
+    }
+}
+
+/// Removes every node that none of `outputs` is computed from, renumbering the
+/// rest in their original order. The result maps each old id to its new one,
+/// or `None` for a removed node, so ids held elsewhere (e.g. by a `Layer`)
+/// can be updated; nodes to keep, such as parameters, must be in `outputs`
+/// or among their inputs.
+pub fn prune<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    outputs: &[usize],
+) -> Vec<Option<usize>> {
+    let keep = reachable(map, nodes.len(), outputs, &[]);
+    let ids = renumber(&keep);
+
+    let mut kept = keep.iter();
+    nodes.retain(|_| *kept.next().unwrap());
+    for (id, node) in nodes.iter_mut().enumerate() {
+        node.id = id;
+    }
+
+    *map = map
+        .drain()
+        .filter_map(|(id, children)| {
+            let id = ids[id]?;
+            // Every input of a kept node was kept as well.
+            Some((id, children.iter().map(|&c| ids[c].unwrap()).collect()))
+        })
+        .collect();
+
+    ids
+}
+
+/// Copies the nodes between `inputs` and `outputs` into a standalone arena
+/// and parent map, e.g. to separate a trained model from its loss. Each of
+/// `inputs` becomes a leaf of the copy, even if it was computed, and the
+/// search stops there; other leaves that `outputs` depend on, such as
+/// parameters, come along with their values. The id map is as in `prune`.
+pub fn extract<T: Float>(
+    map: &Map,
+    nodes: &[Node<T>],
+    inputs: &[usize],
+    outputs: &[usize],
+) -> (Map, Vec<Node<T>>, Vec<Option<usize>>) {
+    let keep = reachable(map, nodes.len(), outputs, inputs);
+    let ids = renumber(&keep);
+
+    let mut extracted_map = Map::new();
+    let mut extracted = Vec::new();
+
+    for (old, node) in nodes.iter().enumerate() {
+        let Some(id) = ids[old] else {
+            continue;
+        };
+        let mut node = node.clone();
+        node.id = id;
+
+        match map.get(&old) {
+            Some(children) if !inputs.contains(&old) => {
+                let children = children.iter().map(|&c| ids[c].unwrap()).collect();
+                extracted_map.insert(id, children);
+            }
+            _ => node.operator = None,
+        }
+        extracted.push(node);
+    }
+
+    (extracted_map, extracted, ids)
+}
+
+// Marks `outputs` and every node they are computed from, without looking past
+// the nodes in `stop`.
+fn reachable(map: &Map, len: usize, outputs: &[usize], stop: &[usize]) -> Vec<bool> {
+    let mut keep = vec![false; len];
+    let mut stack = outputs.to_vec();
+
+    while let Some(id) = stack.pop() {
+        if !keep[id] {
+            keep[id] = true;
+            if let (false, Some(children)) = (stop.contains(&id), map.get(&id)) {
+                stack.extend(children.iter().copied());
+            }
+        }
+    }
+
+    keep
+}
+
+// New, consecutive ids for the kept nodes, in their original order.
+fn renumber(keep: &[bool]) -> Vec<Option<usize>> {
+    let mut next = 0;
+    keep.iter()
+        .map(|&kept| {
+            kept.then(|| {
+                next += 1;
+                next - 1
+            })
+        })
+        .collect()
+}
+
+/// Resets every gradient to zero, e.g. between training steps, since
+/// `backwards` adds to whatever gradients are already there.
+pub fn zero_grad<T: Float>(nodes: &mut [Node<T>]) {
+    for node in nodes.iter_mut() {
+        node.gradient = T::zero();
+    }
+}
+
+/// `zero_grad` for only the nodes in `ids`.
+pub fn zero_grad_of<T: Float>(nodes: &mut [Node<T>], ids: &[usize]) {
+    for &id in ids {
+        nodes[id].gradient = T::zero();
+    }
+}
+
+/// Brings every node up to date after `set_value` calls, recomputing only
+/// nodes downstream of a changed entry.
+pub fn forward<T: Float>(map: &Map, nodes: &mut [Node<T>]) {
+    forward_with_hooks(map, nodes, &Hooks::new());
+}
+
+/// `forward`, running the forward hooks of every recomputed node.
+pub fn forward_with_hooks<T: Float>(map: &Map, nodes: &mut [Node<T>], hooks: &Hooks<T>) {
+    let mut changed = vec![false; nodes.len()];
+
+    for node_id in 0..nodes.len() {
+        let stale = map
+            .get(&node_id)
+            .is_some_and(|children| children.iter().any(|&c| changed[c]));
+
+        if stale {
+            recompute(map, nodes, node_id, hooks);
+        }
+
+        changed[node_id] = stale || nodes[node_id].dirty;
+        nodes[node_id].dirty = false;
+    }
+}
+
+/// Recomputes `node` from the current values of the leaves it depends on,
+/// updating only the nodes on those paths, and returns its new value.
+pub fn eval<T: Float>(map: &Map, nodes: &mut [Node<T>], node: usize) -> T {
+    eval_with_hooks(map, nodes, node, &Hooks::new())
+}
+
+/// `eval`, running the forward hooks of every recomputed node.
+pub fn eval_with_hooks<T: Float>(
+    map: &Map,
+    nodes: &mut [Node<T>],
+    node: usize,
+    hooks: &Hooks<T>,
+) -> T {
     let mut visited = HashSet::new();
-    let mut deque = VecDeque::new();
+    let mut stack = vec![node];
+
+    while let Some(node_id) = stack.pop() {
+        if visited.insert(node_id) {
+            if let Some(children) = map.get(&node_id) {
+                stack.extend(children.iter().copied());
+            }
+        }
+    }
+
+    // Children are always created before the nodes that use them, so
+    // ascending ids are a valid evaluation order.
+    let mut order: Vec<usize> = visited.into_iter().collect();
+    order.sort_unstable();
+
+    for node_id in order {
+        recompute(map, nodes, node_id, hooks);
+    }
+
+    nodes[node].value
+}
+
+/// Sets the gradient of `output` to 1 and accumulates its derivative into
+/// every node it was computed from. Nodes created after `output`, or not among
+/// its inputs, are left alone.
+pub fn backwards<T: Float>(map: &mut Map, nodes: &mut [Node<T>], output: usize) {
+    backwards_with_overrides(map, nodes, output, &Overrides::new());
+}
+
+/// Like `backwards`, but nodes with an entry in `overrides` use that function
+/// instead of their operator's gradient rule.
+pub fn backwards_with_overrides<T: Float>(
+    map: &mut Map,
+    nodes: &mut [Node<T>],
+    output: usize,
+    overrides: &Overrides<T>,
+) {
+    backpropagate(map, nodes, &[(output, T::one())], overrides, &Hooks::new());
+}
 
-    let last_node = nodes.last().clone().unwrap();
-    deque.push_back(last_node.id);
+/// Like `backwards`, but every gradient contribution passes through the
+/// backward hooks of the node receiving it.
+pub fn backwards_with_hooks<T: Float>(
+    map: &mut Map,
+    nodes: &mut [Node<T>],
+    output: usize,
+    hooks: &Hooks<T>,
+) {
+    backpropagate(map, nodes, &[(output, T::one())], &HashMap::new(), hooks);
+}
+
+/// Backward pass from several outputs at once, each starting from its own
+/// seed gradient, e.g. the loss gradient of every output of a `Layer`. Every
+/// node is visited once, however many outputs depend on it. An output listed
+/// twice receives the sum of its seeds.
+pub fn backwards_multi<T: Float>(map: &mut Map, nodes: &mut [Node<T>], seeds: &[(usize, T)]) {
+    backpropagate(map, nodes, seeds, &HashMap::new(), &Hooks::new());
+}
+
+/// Row `i` holds the gradient of `outputs[i]` with respect to each of
+/// `inputs`, from one backward pass per output. Gradients are cleared before
+/// each pass and restored afterwards, so the nodes are left as they were.
+pub fn jacobian<T: Float>(
+    map: &mut Map,
+    nodes: &mut [Node<T>],
+    outputs: &[usize],
+    inputs: &[usize],
+) -> Vec<Vec<T>> {
+    let saved: Vec<T> = nodes.iter().map(|n| n.gradient).collect();
+
+    let rows = outputs
+        .iter()
+        .map(|&output| {
+            zero_grad(nodes);
+            backwards(map, nodes, output);
+            inputs.iter().map(|&x| nodes[x].gradient).collect()
+        })
+        .collect();
 
-    while let Some(node_id) = deque.pop_front() {
-        println!("{:?}", node_id);
+    for (node, gradient) in nodes.iter_mut().zip(saved) {
+        node.gradient = gradient;
+    }
+
+    rows
+}
+
+pub(crate) fn backpropagate<T: Float>(
+    map: &Map,
+    nodes: &mut [Node<T>],
+    seeds: &[(usize, T)],
+    overrides: &Overrides<T>,
+    hooks: &Hooks<T>,
+) {
+    let Some((root, reached)) = seed(map, nodes, seeds) else {
+        return;
+    };
+
+    // Children are always created before the nodes that use them, so walking
+    // the tape from the root down to id 0 finishes each node's gradient
+    // before it is passed on, however often the node is reused.
+
+    for node_id in (0..=root).rev() {
+        if !reached[node_id] {
+            continue;
+        }
         let node_clone = nodes[node_id].clone();
 
-        if !visited.contains(&node_clone.id) {
-            visited.insert(node_clone.id);
-
-            match map.get(&node_clone.id) {
-                Some(child_nodes) => {
-                    let children = get_child_nodes(nodes, child_nodes[0], child_nodes[1]);
-
-                    match children {
-                        [Some(self_node), Some(other_node)] => {
-                            deque.extend([self_node.id, other_node.id]);
-
-                            match &node_clone.operator {
-                                Some(Operator::Plus) => {
-                                    self_node.gradient += node_clone.gradient;
-                                    other_node.gradient += node_clone.gradient;
-                                }
-                                Some(Operator::Mul) => {
-                                    self_node.gradient += other_node.value * node_clone.gradient;
-                                    other_node.gradient += self_node.value * node_clone.gradient;
-                                }
-                                Some(Operator::Pow) => {
-                                    self_node.gradient += other_node.value
-                                        * self_node.value.powf(1.0 - other_node.value)
-                                        * node_clone.gradient;
-                                }
-                                _ => {}
-                            }
-                        }
-                        [Some(self_node), None] => {
-                            deque.extend([self_node.id]);
-
-                            match &node_clone.operator {
-                                Some(Operator::Relu) => {
-                                    if node_clone.value > 0.0 {
-                                        self_node.gradient += node_clone.gradient;
-                                    }
-                                }
-                                _ => {}
-                            }
-                        }
-                        _ => {}
-                    }
+        let Some(children) = map.get(&node_clone.id) else {
+            continue;
+        };
+        let inputs: SmallVec<[T; 2]> = children.iter().map(|&c| nodes[c].value).collect();
+
+        let gradients: SmallVec<[T; 2]> =
+            match (overrides.get(&node_clone.id), &node_clone.operator) {
+                (Some(backward), _) => backward(&node_clone, &inputs).into_iter().collect(),
+                (None, Some(operator)) if !operator.stops_gradient() => {
+                    operator.backward(node_clone.value, node_clone.gradient, &inputs)
                 }
-                None => {}
+                _ => continue,
             };
+
+        // Gradients are written by index, so children may appear in any
+        // order and the same child may appear more than once.
+        for (&child, gradient) in children.iter().zip(gradients) {
+            if nodes[child].requires_grad {
+                anomaly::check_gradient(map, nodes, node_id, child, gradient);
+                nodes[child].gradient += hooks.run_backward(child, gradient);
+            }
+        }
+    }
+}
+
+// Starts a backward pass from `seeds`, shared by `backpropagate` and
+// `parallel::backwards_parallel`. Every computed node the seeds reach starts
+// over from zero, so a second pass does not propagate the first one's
+// gradient again, while leaves keep accumulating across passes until
+// `zero_grad`. Seeded nodes are set to their seed. Returns the highest
+// seeded id and which nodes up to it are reached.
+fn seed<T: Float>(
+    map: &Map,
+    nodes: &mut [Node<T>],
+    seeds: &[(usize, T)],
+) -> Option<(usize, Vec<bool>)> {
+    let root = seeds.iter().map(|&(id, _)| id).max()?;
+
+    let mut reached = vec![false; root + 1];
+    for &(id, _) in seeds {
+        reached[id] = true;
+        nodes[id].gradient = T::zero();
+    }
+
+    for node_id in (0..=root).rev() {
+        if !reached[node_id] {
+            continue;
+        }
+        if nodes[node_id].operator.is_some() {
+            nodes[node_id].gradient = T::zero();
+        }
+        for &child in followed(map, nodes, node_id) {
+            reached[child] = true;
         }
     }
+
+    for &(id, seed) in seeds {
+        nodes[id].gradient += seed;
+    }
+
+    Some((root, reached))
 }
@@ -0,0 +1,57 @@
+use super::{Map, Node};
+use crate::float::Float;
+use std::cell::Cell;
+
+thread_local! {
+    static ENABLED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Turns anomaly detection on or off for the current thread. While it is on,
+/// building or recomputing a node that comes out NaN or infinite, and any
+/// NaN or infinite gradient contribution in `backwards`, panics with the
+/// offending node id, its operator and the values of its inputs, instead of
+/// letting the bad value spread through the rest of the graph.
+///
+/// `backwards_parallel` is only checked on graphs it hands to the serial pass.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.with(|flag| flag.set(enabled));
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.with(|flag| flag.get())
+}
+
+// The operator name and input values of `node`, for the panic messages.
+fn describe<T: Float>(map: &Map, nodes: &[Node<T>], node: usize) -> String {
+    let operator = nodes[node].operator.as_ref().map_or("leaf", |op| op.name());
+    let inputs: Vec<T> = map
+        .get(&node)
+        .map(|children| children.iter().map(|&c| nodes[c].value).collect())
+        .unwrap_or_default();
+
+    format!("node {} ({}) with inputs {:?}", node, operator, inputs)
+}
+
+pub(super) fn check_value<T: Float>(map: &Map, nodes: &[Node<T>], node: usize) {
+    let value = nodes[node].value;
+    if is_enabled() && !value.is_finite() {
+        panic!("anomaly: {} produced {}", describe(map, nodes, node), value);
+    }
+}
+
+pub(super) fn check_gradient<T: Float>(
+    map: &Map,
+    nodes: &[Node<T>],
+    node: usize,
+    child: usize,
+    gradient: T,
+) {
+    if is_enabled() && !gradient.is_finite() {
+        panic!(
+            "anomaly: {} passed gradient {} to node {}",
+            describe(map, nodes, node),
+            gradient,
+            child
+        );
+    }
+}
@@ -0,0 +1,43 @@
+use super::{new_node, Activation, Layer, Neuron, Node};
+use ndarray::{Array1, Array2};
+
+pub fn nodes_from_array1(nodes: &mut Vec<Node>, values: &Array1<f64>) -> Vec<usize> {
+    values.iter().map(|&v| new_node(nodes, v)).collect()
+}
+
+pub fn values_to_array1(nodes: &[Node], ids: &[usize]) -> Array1<f64> {
+    ids.iter().map(|&id| nodes[id].value).collect()
+}
+
+impl Layer {
+    /// Builds a layer whose weights come from a `(count_out, count_in)` matrix
+    /// and whose biases come from a `count_out` vector.
+    pub fn from_array2(nodes: &mut Vec<Node>, weights: &Array2<f64>, bias: &Array1<f64>) -> Self {
+        assert!(weights.nrows() == bias.len());
+
+        let neurons = weights
+            .rows()
+            .into_iter()
+            .zip(bias.iter())
+            .map(|(row, &b)| Neuron {
+                weights: row.iter().map(|&w| new_node(nodes, w)).collect(),
+                bias: new_node(nodes, b),
+                activation: Activation::Relu,
+            })
+            .collect();
+
+        Layer { neurons }
+    }
+
+    pub fn weights_to_array2(&self, nodes: &[Node]) -> Array2<f64> {
+        let count_in = self.neurons.first().map_or(0, |n| n.weights.len());
+
+        Array2::from_shape_fn((self.neurons.len(), count_in), |(i, j)| {
+            nodes[self.neurons[i].weights[j]].value
+        })
+    }
+
+    pub fn bias_to_array1(&self, nodes: &[Node]) -> Array1<f64> {
+        self.neurons.iter().map(|n| nodes[n.bias].value).collect()
+    }
+}
@@ -0,0 +1,79 @@
+use super::{custom, CustomOp, Map, Node};
+use crate::graph::Graph;
+use std::fmt;
+use std::sync::Arc;
+
+/// Builds a segment of the computation on the graph it is given;
+/// see `checkpoint`.
+pub type Segment = Arc<dyn Fn(&mut Graph, &[usize]) -> Vec<usize> + Send + Sync>;
+
+// One output of a checkpointed segment. Both passes rebuild the segment in a
+// scratch graph that is dropped again straight away.
+struct Checkpoint {
+    segment: Segment,
+    output: usize,
+}
+
+impl Checkpoint {
+    fn run(&self, inputs: &[f64]) -> (Graph, Vec<usize>, usize) {
+        let mut graph = Graph::new();
+        let leaves: Vec<usize> = inputs.iter().map(|&x| graph.leaf(x)).collect();
+        let outputs = (self.segment)(&mut graph, &leaves);
+        (graph, leaves, outputs[self.output])
+    }
+}
+
+impl fmt::Debug for Checkpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Checkpoint({})", self.output)
+    }
+}
+
+impl CustomOp for Checkpoint {
+    fn name(&self) -> &'static str {
+        "checkpoint"
+    }
+
+    fn forward(&self, inputs: &[f64]) -> f64 {
+        let (graph, _, output) = self.run(inputs);
+        graph.value(output)
+    }
+
+    fn backward(&self, _value: f64, gradient: f64, inputs: &[f64]) -> Vec<f64> {
+        let (mut graph, leaves, output) = self.run(inputs);
+        graph.backward(output);
+        leaves
+            .iter()
+            .map(|&x| graph.gradient(x) * gradient)
+            .collect()
+    }
+}
+
+/// Adds `segment` to the graph as one node per output, each depending
+/// directly on `inputs`, instead of one node per op. The intermediate values
+/// are not stored: `segment` is run again on a scratch graph, given one leaf
+/// per input, whenever an output is recomputed or backpropagated through.
+///
+/// Memory stays bounded by the segment boundaries at the cost of running the
+/// segment once per output in every pass. Everything the segment reads,
+/// parameters included, has to be among `inputs`.
+pub fn checkpoint(
+    map: &mut Map,
+    nodes: &mut Vec<Node>,
+    inputs: &[usize],
+    segment: Segment,
+) -> Vec<usize> {
+    let mut probe = Graph::new();
+    let leaves: Vec<usize> = inputs.iter().map(|&x| probe.leaf(nodes[x].value)).collect();
+    let count = segment(&mut probe, &leaves).len();
+
+    (0..count)
+        .map(|output| {
+            let op = Checkpoint {
+                segment: segment.clone(),
+                output,
+            };
+            custom(map, nodes, Arc::new(op), inputs).0
+        })
+        .collect()
+}
@@ -0,0 +1,68 @@
+use super::{Map, Node};
+use crate::float::Float;
+use smallvec::SmallVec;
+
+/// A value together with its derivative along one input direction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Dual<T = f64> {
+    pub value: T,
+    pub tangent: T,
+}
+
+/// Jacobian-vector product by forward-mode differentiation: evaluates the
+/// graph on dual numbers whose tangents start at `seeds` (zero for every
+/// other leaf) and returns the value and directional derivative of each of
+/// `outputs`. One pass covers any number of outputs, where `backwards` would
+/// need one per output.
+///
+/// Each node's tangent is its operator's partial derivatives, taken from the
+/// same rules `backwards` uses, applied to the tangents of its inputs. Nodes
+/// are read but not modified.
+pub fn jvp<T: Float>(
+    map: &Map,
+    nodes: &[Node<T>],
+    seeds: &[(usize, T)],
+    outputs: &[usize],
+) -> Vec<Dual<T>> {
+    let Some(&last) = outputs.iter().max() else {
+        return Vec::new();
+    };
+
+    let mut duals: Vec<Dual<T>> = nodes[..=last]
+        .iter()
+        .map(|node| Dual {
+            value: node.value,
+            tangent: T::zero(),
+        })
+        .collect();
+    for &(id, tangent) in seeds {
+        duals[id].tangent += tangent;
+    }
+
+    // Ascending ids visit every input before the nodes computed from it.
+    for node_id in 0..=last {
+        let (Some(operator), Some(children)) = (&nodes[node_id].operator, map.get(&node_id)) else {
+            continue;
+        };
+
+        let inputs: SmallVec<[T; 2]> = children.iter().map(|&c| duals[c].value).collect();
+        let value = operator.forward(&inputs);
+
+        // `backward` is linear in the gradient, so a gradient of one yields
+        // the partial derivative with respect to each input.
+        let tangent = if operator.stops_gradient() {
+            T::zero()
+        } else {
+            operator
+                .backward(value, T::one(), &inputs)
+                .into_iter()
+                .zip(children)
+                .map(|(partial, &c)| partial * duals[c].tangent)
+                .sum()
+        };
+
+        duals[node_id] = Dual { value, tangent };
+    }
+
+    outputs.iter().map(|&id| duals[id]).collect()
+}
@@ -0,0 +1,369 @@
+use super::{Activation, Layer, Map, Node};
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+fn weights(nodes: &[Node], layer: &Layer) -> Vec<Vec<f64>> {
+    layer
+        .neurons
+        .iter()
+        .map(|n| n.weights.iter().map(|&w| nodes[w].value).collect())
+        .collect()
+}
+
+fn biases(nodes: &[Node], layer: &Layer) -> Vec<f64> {
+    layer.neurons.iter().map(|n| nodes[n.bias].value).collect()
+}
+
+// `{:?}` prints the shortest representation that round-trips, and always
+// includes a decimal point or exponent, so it is a valid float literal. There
+// are no literals for the non-finite values, so those name the constants.
+fn rust_float(value: f64) -> String {
+    if value.is_nan() {
+        "f64::NAN".to_string()
+    } else if value == f64::INFINITY {
+        "f64::INFINITY".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "f64::NEG_INFINITY".to_string()
+    } else {
+        format!("{:?}", value)
+    }
+}
+
+// `NAN` and `INFINITY` are the C99 macros from `<math.h>`.
+fn c_float(value: f64) -> String {
+    if value.is_nan() {
+        "NAN".to_string()
+    } else if value == f64::INFINITY {
+        "INFINITY".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-INFINITY".to_string()
+    } else {
+        format!("{:?}", value)
+    }
+}
+
+fn list(values: &[f64], float: fn(f64) -> String) -> String {
+    values
+        .iter()
+        .map(|&v| float(v))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Activations are told apart by the bits of their parameter, so that
+// `Elu(f64::NAN)` matches itself.
+fn same(a: Activation, b: Activation) -> bool {
+    match (a, b) {
+        (Activation::Elu(x), Activation::Elu(y)) => x.to_bits() == y.to_bits(),
+        _ => a == b,
+    }
+}
+
+// Activations with a parameter get one generated function per distinct value,
+// numbered by their position in `used`.
+fn activation_name(used: &[Activation], activation: Activation) -> String {
+    match activation {
+        Activation::Relu => "activate_relu".to_string(),
+        Activation::Tanh => "activate_tanh".to_string(),
+        Activation::Linear => "activate_linear".to_string(),
+        Activation::Elu(_) => {
+            let index = used.iter().position(|&a| same(a, activation)).unwrap();
+            format!("activate_elu{}", index)
+        }
+    }
+}
+
+fn rust_activation(used: &[Activation], activation: Activation) -> String {
+    let name = activation_name(used, activation);
+    let body = match activation {
+        Activation::Relu => "if x > 0.0 { x } else { 0.0 }".to_string(),
+        Activation::Tanh => "x.tanh()".to_string(),
+        Activation::Linear => "x".to_string(),
+        Activation::Elu(alpha) => format!(
+            "if x > 0.0 {{ x }} else {{ {} * (x.exp() - 1.0) }}",
+            rust_float(alpha)
+        ),
+    };
+    format!("fn {}(x: f64) -> f64 {{\n    {}\n}}\n", name, body)
+}
+
+fn c_activation(used: &[Activation], activation: Activation) -> String {
+    let name = activation_name(used, activation);
+    let body = match activation {
+        Activation::Relu => "x > 0.0 ? x : 0.0".to_string(),
+        Activation::Tanh => "tanh(x)".to_string(),
+        Activation::Linear => "x".to_string(),
+        Activation::Elu(alpha) => format!("x > 0.0 ? x : {} * (exp(x) - 1.0)", c_float(alpha)),
+    };
+    format!(
+        "static double {}(double x) {{\n    return {};\n}}\n",
+        name, body
+    )
+}
+
+fn needs_math_h(activation: &Activation) -> bool {
+    matches!(activation, Activation::Tanh | Activation::Elu(_))
+}
+
+// The distinct activations used by `layers`, so only those get emitted and
+// the generated code has no unused functions.
+fn activations(layers: &[&Layer]) -> Vec<Activation> {
+    let mut used = Vec::new();
+    for layer in layers {
+        if !used.iter().any(|&a| same(a, layer.activation())) {
+            used.push(layer.activation());
+        }
+    }
+    used
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+// The input and output sizes of the whole stack, once every layer has a
+// neuron and feeds the next one the number of inputs it takes.
+fn check_layers(layers: &[&Layer]) -> io::Result<(usize, usize)> {
+    if layers.is_empty() {
+        return Err(invalid("no layers to export".to_string()));
+    }
+    if let Some(i) = layers.iter().position(|layer| layer.neurons.is_empty()) {
+        return Err(invalid(format!("layer {} has no neurons", i)));
+    }
+
+    for (i, pair) in layers.windows(2).enumerate() {
+        if pair[0].count_out() != pair[1].count_in() {
+            return Err(invalid(format!(
+                "layer {} has {} outputs, layer {} takes {} inputs",
+                i,
+                pair[0].count_out(),
+                i + 1,
+                pair[1].count_in()
+            )));
+        }
+    }
+
+    Ok((layers[0].count_in(), layers[layers.len() - 1].count_out()))
+}
+
+/// Emits a standalone Rust module with the layers' weights as constants and a
+/// dependency-free `predict` that applies them in order, matching
+/// `Layer::predict`. Fails on an empty stack, a layer without neurons, or
+/// layers whose sizes do not line up.
+pub fn rust_source(nodes: &[Node], layers: &[&Layer]) -> io::Result<String> {
+    let (count_in, count_out) = check_layers(layers)?;
+    let mut out = String::new();
+
+    writeln!(out, "// Generated by rustygrad. Do not edit.").unwrap();
+    writeln!(out).unwrap();
+
+    for (i, layer) in layers.iter().enumerate() {
+        let rows = weights(nodes, layer);
+        let (outs, ins) = (rows.len(), rows[0].len());
+
+        writeln!(
+            out,
+            "const LAYER_{}_WEIGHTS: [[f64; {}]; {}] = [",
+            i, ins, outs
+        )
+        .unwrap();
+        for row in &rows {
+            writeln!(out, "    [{}],", list(row, rust_float)).unwrap();
+        }
+        writeln!(out, "];").unwrap();
+        writeln!(
+            out,
+            "const LAYER_{}_BIAS: [f64; {}] = [{}];",
+            i,
+            outs,
+            list(&biases(nodes, layer), rust_float)
+        )
+        .unwrap();
+        writeln!(out).unwrap();
+    }
+
+    out.push_str(
+        "fn dense<const I: usize, const O: usize>(
+    weights: &[[f64; I]; O],
+    bias: &[f64; O],
+    x: &[f64; I],
+    activation: fn(f64) -> f64,
+) -> [f64; O] {
+    let mut y = [0.0; O];
+    for o in 0..O {
+        let mut sum = bias[o];
+        for i in 0..I {
+            sum += weights[o][i] * x[i];
+        }
+        y[o] = activation(sum);
+    }
+    y
+}
+
+",
+    );
+
+    let used = activations(layers);
+    for &activation in &used {
+        writeln!(out, "{}", rust_activation(&used, activation)).unwrap();
+    }
+
+    writeln!(
+        out,
+        "pub fn predict(x: &[f64; {}]) -> [f64; {}] {{",
+        count_in, count_out
+    )
+    .unwrap();
+    writeln!(out, "    let h = x;").unwrap();
+    for (i, layer) in layers.iter().enumerate() {
+        writeln!(
+            out,
+            "    let h = &dense(&LAYER_{0}_WEIGHTS, &LAYER_{0}_BIAS, h, {1});",
+            i,
+            activation_name(&used, layer.activation())
+        )
+        .unwrap();
+    }
+    writeln!(out, "    *h").unwrap();
+    writeln!(out, "}}").unwrap();
+
+    Ok(out)
+}
+
+pub fn write_rust<P: AsRef<Path>>(path: P, nodes: &[Node], layers: &[&Layer]) -> io::Result<()> {
+    fs::write(path, rust_source(nodes, layers)?)
+}
+
+/// Emits a portable C99 file with the layers' weights as static arrays and a
+/// `void predict(const double *x, double *y)` built from plain loops, matching
+/// `Layer::predict`. Fails as `rust_source` does.
+pub fn c_source(nodes: &[Node], layers: &[&Layer]) -> io::Result<String> {
+    let (count_in, count_out) = check_layers(layers)?;
+    let mut out = String::new();
+
+    writeln!(out, "/* Generated by rustygrad. Do not edit. */").unwrap();
+    let used = activations(layers);
+    let non_finite = layers
+        .iter()
+        .flat_map(|layer| layer.parameters())
+        .any(|p| !nodes[p].value.is_finite());
+    if non_finite || used.iter().any(needs_math_h) {
+        writeln!(out, "#include <math.h>").unwrap();
+    }
+    writeln!(out, "#include <stddef.h>").unwrap();
+    writeln!(out).unwrap();
+
+    for (i, layer) in layers.iter().enumerate() {
+        let rows = weights(nodes, layer);
+        let (outs, ins) = (rows.len(), rows[0].len());
+
+        writeln!(
+            out,
+            "static const double LAYER_{}_WEIGHTS[{}][{}] = {{",
+            i, outs, ins
+        )
+        .unwrap();
+        for row in &rows {
+            writeln!(out, "    {{{}}},", list(row, c_float)).unwrap();
+        }
+        writeln!(out, "}};").unwrap();
+        writeln!(
+            out,
+            "static const double LAYER_{}_BIAS[{}] = {{{}}};",
+            i,
+            outs,
+            list(&biases(nodes, layer), c_float)
+        )
+        .unwrap();
+        writeln!(out).unwrap();
+    }
+
+    out.push_str(
+        "static void dense(const double *weights, const double *bias, size_t count_in,
+                  size_t count_out, const double *x, double *y,
+                  double (*activation)(double)) {
+    for (size_t o = 0; o < count_out; o++) {
+        double sum = bias[o];
+        for (size_t i = 0; i < count_in; i++) {
+            sum += weights[o * count_in + i] * x[i];
+        }
+        y[o] = activation(sum);
+    }
+}
+
+",
+    );
+
+    for &activation in &used {
+        writeln!(out, "{}", c_activation(&used, activation)).unwrap();
+    }
+
+    writeln!(
+        out,
+        "/* x has {} elements, y receives {}. */",
+        count_in, count_out
+    )
+    .unwrap();
+    writeln!(out, "void predict(const double *x, double *y) {{").unwrap();
+    let mut input = "x".to_string();
+    for (i, layer) in layers.iter().enumerate() {
+        let outs = layer.neurons.len();
+        let ins = layer.neurons[0].weights.len();
+
+        let output = if i == layers.len() - 1 {
+            "y".to_string()
+        } else {
+            writeln!(out, "    double h{}[{}];", i, outs).unwrap();
+            format!("h{}", i)
+        };
+
+        writeln!(
+            out,
+            "    dense(&LAYER_{0}_WEIGHTS[0][0], LAYER_{0}_BIAS, {1}, {2}, {3}, {4}, {5});",
+            i,
+            ins,
+            outs,
+            input,
+            output,
+            activation_name(&used, layer.activation())
+        )
+        .unwrap();
+        input = output;
+    }
+    writeln!(out, "}}").unwrap();
+
+    Ok(out)
+}
+
+pub fn write_c<P: AsRef<Path>>(path: P, nodes: &[Node], layers: &[&Layer]) -> io::Result<()> {
+    fs::write(path, c_source(nodes, layers)?)
+}
+
+/// Renders the graph in Graphviz DOT format, one box per node labelled with
+/// its operator, value and gradient, with edges from inputs to their users.
+pub fn dot(map: &Map, nodes: &[Node]) -> String {
+    let mut out = String::from("digraph {\n    rankdir=LR;\n");
+
+    for node in nodes {
+        let name = node.operator.as_ref().map_or("leaf", |op| op.name());
+        writeln!(
+            out,
+            "    n{} [shape=box, label=\"#{} {} | value {:.4} | grad {:.4}\"];",
+            node.id, node.id, name, node.value, node.gradient
+        )
+        .unwrap();
+    }
+
+    for (node_id, node) in nodes.iter().enumerate() {
+        if let Some(children) = map.get(&node.id) {
+            for child in children {
+                writeln!(out, "    n{} -> n{};", child, node_id).unwrap();
+            }
+        }
+    }
+
+    out.push_str("}\n");
+    out
+}
@@ -0,0 +1,58 @@
+use super::{eval, jacobian, set_value, Map, Node};
+use crate::float::Float;
+
+/// A parameter whose analytic gradient disagrees with the finite-difference
+/// estimate; see `gradcheck`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch<T = f64> {
+    pub parameter: usize,
+    pub analytic: T,
+    pub numeric: T,
+}
+
+/// Compares the gradient `backwards` computes for each of `params` against
+/// the central difference `(f(p + eps) - f(p - eps)) / 2eps` of `output`. A
+/// parameter is reported when the two differ by more than
+/// `tol * max(1, |numeric|)`.
+///
+/// Every parameter is restored to its value and the gradients to what they
+/// were, so the check can run in the middle of training.
+pub fn gradcheck<T: Float>(
+    map: &mut Map,
+    nodes: &mut [Node<T>],
+    output: usize,
+    params: &[usize],
+    eps: T,
+    tol: T,
+) -> Vec<Mismatch<T>> {
+    let analytic = jacobian(map, nodes, &[output], params).remove(0);
+    let two = T::from_f64(2.0);
+
+    let mismatches = params
+        .iter()
+        .zip(analytic)
+        .filter_map(|(&parameter, analytic)| {
+            let original = nodes[parameter].value;
+
+            set_value(nodes, parameter, original + eps);
+            let above = eval(map, nodes, output);
+            set_value(nodes, parameter, original - eps);
+            let below = eval(map, nodes, output);
+            set_value(nodes, parameter, original);
+
+            let numeric = (above - below) / (two * eps);
+            let bound = tol * numeric.abs().max(T::one());
+            // Written so that a NaN on either side counts as a mismatch.
+            let agrees = (analytic - numeric).abs() <= bound;
+
+            (!agrees).then_some(Mismatch {
+                parameter,
+                analytic,
+                numeric,
+            })
+        })
+        .collect();
+
+    eval(map, nodes, output);
+    mismatches
+}
@@ -0,0 +1,99 @@
+use super::Node;
+use crate::float::Float;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Called with a node's id and new value whenever `forward` or `eval`
+/// recomputes it.
+pub type ForwardHook<T = f64> = Box<dyn Fn(usize, T)>;
+
+/// Called with a node's id and each gradient contribution `backwards` is about
+/// to add to it. The returned value is what gets accumulated, so returning the
+/// input unchanged observes the gradient and returning something else
+/// rewrites it (e.g. masks or clips it).
+pub type BackwardHook<T = f64> = Box<dyn Fn(usize, T) -> T>;
+
+/// Per-node callbacks for the `*_with_hooks` passes. Hooks on a node run in
+/// the order they were registered.
+pub struct Hooks<T = f64> {
+    forward: HashMap<usize, Vec<ForwardHook<T>>>,
+    backward: HashMap<usize, Vec<BackwardHook<T>>>,
+}
+
+impl<T: Float> Hooks<T> {
+    pub fn new() -> Self {
+        Hooks {
+            forward: HashMap::new(),
+            backward: HashMap::new(),
+        }
+    }
+
+    pub fn on_forward(&mut self, node: usize, hook: impl Fn(usize, T) + 'static) {
+        self.forward.entry(node).or_default().push(Box::new(hook));
+    }
+
+    pub fn on_backward(&mut self, node: usize, hook: impl Fn(usize, T) -> T + 'static) {
+        self.backward.entry(node).or_default().push(Box::new(hook));
+    }
+
+    /// Removes every hook attached to `node`.
+    pub fn remove(&mut self, node: usize) {
+        self.forward.remove(&node);
+        self.backward.remove(&node);
+    }
+
+    /// Removes the hooks of every node with an id of `len` or more.
+    pub fn truncate(&mut self, len: usize) {
+        self.forward.retain(|&node, _| node < len);
+        self.backward.retain(|&node, _| node < len);
+    }
+
+    /// Moves the hooks of each node to `ids[node]`, dropping those of nodes
+    /// mapped to `None`; see `node::prune`.
+    pub fn remap(&mut self, ids: &[Option<usize>]) {
+        remap(&mut self.forward, ids);
+        remap(&mut self.backward, ids);
+    }
+
+    pub fn clear(&mut self) {
+        self.forward.clear();
+        self.backward.clear();
+    }
+
+    pub(super) fn run_forward(&self, nodes: &[Node<T>], node: usize) {
+        if let Some(hooks) = self.forward.get(&node) {
+            for hook in hooks {
+                hook(node, nodes[node].value);
+            }
+        }
+    }
+
+    pub(super) fn run_backward(&self, node: usize, gradient: T) -> T {
+        match self.backward.get(&node) {
+            Some(hooks) => hooks.iter().fold(gradient, |g, hook| hook(node, g)),
+            None => gradient,
+        }
+    }
+}
+
+fn remap<H>(hooks: &mut HashMap<usize, H>, ids: &[Option<usize>]) {
+    *hooks = hooks
+        .drain()
+        .filter_map(|(node, h)| ids.get(node).copied().flatten().map(|id| (id, h)))
+        .collect();
+}
+
+impl<T: Float> Default for Hooks<T> {
+    fn default() -> Self {
+        Hooks::new()
+    }
+}
+
+impl<T> fmt::Debug for Hooks<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("forward", &self.forward.keys().collect::<Vec<_>>())
+            .field("backward", &self.backward.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
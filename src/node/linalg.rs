@@ -0,0 +1,71 @@
+use super::{new_node, Activation, Layer, Neuron, Node};
+use nalgebra::{DMatrix, DVector};
+
+pub fn nodes_from_dvector(nodes: &mut Vec<Node>, values: &DVector<f64>) -> Vec<usize> {
+    values.iter().map(|&v| new_node(nodes, v)).collect()
+}
+
+pub fn values_to_dvector(nodes: &[Node], ids: &[usize]) -> DVector<f64> {
+    DVector::from_iterator(ids.len(), ids.iter().map(|&id| nodes[id].value))
+}
+
+/// A snapshot of a layer's parameters as plain matrices, for inference
+/// without building graph nodes.
+pub struct DenseLayer {
+    pub weights: DMatrix<f64>,
+    pub bias: DVector<f64>,
+    pub activation: Activation,
+}
+
+impl DenseLayer {
+    pub fn forward(&self, x: &DVector<f64>) -> DVector<f64> {
+        (&self.weights * x + &self.bias).map(|v| self.activation.apply(v))
+    }
+}
+
+impl Layer {
+    /// Builds a layer whose weights come from a `(count_out, count_in)` matrix
+    /// and whose biases come from a `count_out` vector.
+    pub fn from_dmatrix(
+        nodes: &mut Vec<Node>,
+        weights: &DMatrix<f64>,
+        bias: &DVector<f64>,
+    ) -> Self {
+        assert!(weights.nrows() == bias.len());
+
+        let neurons = weights
+            .row_iter()
+            .zip(bias.iter())
+            .map(|(row, &b)| Neuron {
+                weights: row.iter().map(|&w| new_node(nodes, w)).collect(),
+                bias: new_node(nodes, b),
+                activation: Activation::Relu,
+            })
+            .collect();
+
+        Layer { neurons }
+    }
+
+    pub fn weights_to_dmatrix(&self, nodes: &[Node]) -> DMatrix<f64> {
+        let count_in = self.neurons.first().map_or(0, |n| n.weights.len());
+
+        DMatrix::from_fn(self.neurons.len(), count_in, |i, j| {
+            nodes[self.neurons[i].weights[j]].value
+        })
+    }
+
+    pub fn bias_to_dvector(&self, nodes: &[Node]) -> DVector<f64> {
+        DVector::from_iterator(
+            self.neurons.len(),
+            self.neurons.iter().map(|n| nodes[n.bias].value),
+        )
+    }
+
+    pub fn to_dense(&self, nodes: &[Node]) -> DenseLayer {
+        DenseLayer {
+            weights: self.weights_to_dmatrix(nodes),
+            bias: self.bias_to_dvector(nodes),
+            activation: self.activation(),
+        }
+    }
+}
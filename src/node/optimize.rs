@@ -0,0 +1,70 @@
+use super::{prune, Children, Map, Node, Operator};
+use crate::float::Float;
+use std::collections::HashMap;
+
+/// Shrinks the graph behind `outputs` before it is run:
+///
+/// - a node whose inputs are all constants (leaves that do not require a
+///   gradient, e.g. from `constant`) becomes a constant itself;
+/// - nodes with the same operator and the same inputs, and constants with the
+///   same value, are merged into the first of them;
+/// - whatever `outputs` no longer depends on is removed with `prune`.
+///
+/// Returns the old-to-new id map, as `prune` does: a merged node maps to the
+/// node it was merged into. Frozen parameters count as constants, so
+/// unfreeze them first if they are to be trained later. Custom ops are
+/// folded but never merged, since their `Debug` output need not identify
+/// them.
+pub fn optimize<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    outputs: &[usize],
+) -> Vec<Option<usize>> {
+    let is_constant = |node: &Node<T>| node.operator.is_none() && !node.requires_grad;
+
+    let mut canonical: Vec<usize> = (0..nodes.len()).collect();
+    let mut seen: HashMap<String, usize> = HashMap::new();
+
+    for id in 0..nodes.len() {
+        let children: Option<Children> = map
+            .get(&id)
+            .map(|children| children.iter().map(|&c| canonical[c]).collect());
+
+        let key = match (nodes[id].operator.clone(), children) {
+            (Some(operator), Some(children)) => {
+                if children.iter().all(|&c| is_constant(&nodes[c])) {
+                    let inputs: Vec<T> = children.iter().map(|&c| nodes[c].value).collect();
+                    nodes[id].value = operator.forward(&inputs);
+                    nodes[id].operator = None;
+                    nodes[id].requires_grad = false;
+                    map.remove(&id);
+                    format!("constant {:?}", nodes[id].value)
+                } else {
+                    let key = match &operator {
+                        Operator::Custom(_) => None,
+                        _ => Some(format!("{:?} {:?}", operator, children)),
+                    };
+                    map.insert(id, children);
+                    match key {
+                        Some(key) => key,
+                        None => continue,
+                    }
+                }
+            }
+            _ if is_constant(&nodes[id]) => format!("constant {:?}", nodes[id].value),
+            _ => continue,
+        };
+
+        match seen.get(&key) {
+            Some(&first) => canonical[id] = first,
+            None => {
+                seen.insert(key, id);
+            }
+        }
+    }
+
+    let roots: Vec<usize> = outputs.iter().map(|&o| canonical[o]).collect();
+    let ids = prune(map, nodes, &roots);
+
+    canonical.into_iter().map(|c| ids[c]).collect()
+}
@@ -0,0 +1,171 @@
+use super::hooks::Hooks;
+use super::{backpropagate, followed, seed, Map, Node, Overrides};
+use crate::float::Float;
+use smallvec::SmallVec;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Barrier;
+use std::thread;
+
+fn atomic_add<T: Float>(cell: &AtomicU64, delta: T) {
+    let mut current = cell.load(Ordering::Relaxed);
+
+    loop {
+        let updated = (T::from_bits64(current) + delta).to_bits64();
+        match cell.compare_exchange_weak(current, updated, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return,
+            Err(actual) => current = actual,
+        }
+    }
+}
+
+/// Groups the nodes a backward pass from `root` walks through (see
+/// `followed`) by their longest distance from it. Every node appears in a
+/// later level than all of the nodes that use it, so once a level is reached
+/// its gradients are complete.
+fn levels<T: Float>(map: &Map, nodes: &[Node<T>], root: usize) -> Vec<Vec<usize>> {
+    let mut depth: Vec<Option<usize>> = vec![None; root + 1];
+    depth[root] = Some(0);
+
+    // Inputs always have smaller ids than the nodes using them, so walking ids
+    // downwards visits every node after all of its users.
+    for node_id in (0..=root).rev() {
+        if let Some(d) = depth[node_id] {
+            for &child in followed(map, nodes, node_id) {
+                depth[child] = Some(depth[child].map_or(d + 1, |c| c.max(d + 1)));
+            }
+        }
+    }
+
+    let mut levels: Vec<Vec<usize>> = Vec::new();
+    for (node_id, d) in depth.into_iter().enumerate() {
+        if let Some(d) = d {
+            if levels.len() <= d {
+                levels.resize(d + 1, Vec::new());
+            }
+            levels[d].push(node_id);
+        }
+    }
+
+    levels
+}
+
+// Levels narrower than this are propagated by a single thread: below it,
+// waking the workers costs more than the level itself.
+const MIN_PARALLEL_WIDTH: usize = 256;
+
+// A step of the pass: a run of consecutive narrow levels handled by one
+// thread, or one wide level split across all of them.
+enum Phase {
+    Serial(Vec<usize>),
+    Parallel(Vec<usize>),
+}
+
+fn phases(levels: Vec<Vec<usize>>) -> Vec<Phase> {
+    let mut phases: Vec<Phase> = Vec::new();
+
+    for level in levels {
+        if level.len() >= MIN_PARALLEL_WIDTH {
+            phases.push(Phase::Parallel(level));
+        } else if let Some(Phase::Serial(run)) = phases.last_mut() {
+            run.extend(level);
+        } else {
+            phases.push(Phase::Serial(level));
+        }
+    }
+
+    phases
+}
+
+// Passes the gradient of `node_id` on to its inputs.
+fn propagate<T: Float>(map: &Map, nodes: &[Node<T>], gradients: &[AtomicU64], node_id: usize) {
+    let node = &nodes[node_id];
+
+    let (Some(operator), Some(children)) = (&node.operator, map.get(&node_id)) else {
+        return;
+    };
+    if operator.stops_gradient() {
+        return;
+    }
+
+    let gradient = T::from_bits64(gradients[node_id].load(Ordering::Relaxed));
+    let inputs: SmallVec<[T; 2]> = children.iter().map(|&c| nodes[c].value).collect();
+
+    let deltas = operator.backward(node.value, gradient, &inputs);
+    for (&child, delta) in children.iter().zip(deltas) {
+        if nodes[child].requires_grad {
+            atomic_add(&gradients[child], delta);
+        }
+    }
+}
+
+/// `node::backwards` from `output`, propagating each topological level
+/// across `threads` worker threads, accumulating gradients atomically.
+/// Gradients start over and accumulate exactly as in `node::backwards`.
+///
+/// The workers live for the whole pass and wait for each other between
+/// levels. Runs of levels narrower than a few hundred nodes are handled by
+/// one worker alone, and a graph without any wide level is handed to the
+/// serial pass instead.
+pub fn backwards_parallel<T: Float>(
+    map: &Map,
+    nodes: &mut [Node<T>],
+    output: usize,
+    threads: usize,
+) {
+    let root = output;
+    let phases = phases(levels(map, nodes, root));
+
+    if threads <= 1 || !phases.iter().any(|p| matches!(p, Phase::Parallel(_))) {
+        let seeds = [(root, T::one())];
+        backpropagate(map, nodes, &seeds, &Overrides::new(), &Hooks::new());
+        return;
+    }
+    seed(map, nodes, &[(root, T::one())]);
+
+    let gradients: Vec<AtomicU64> = nodes
+        .iter()
+        .map(|n| AtomicU64::new(n.gradient.to_bits64()))
+        .collect();
+
+    {
+        let nodes: &[Node<T>] = nodes;
+        let gradients = &gradients;
+
+        // Worker `worker` of `threads`: every phase ends at the barrier, so a
+        // level only starts once all of its users have been propagated.
+        let work = |worker: usize, barrier: &Barrier| {
+            for phase in &phases {
+                match phase {
+                    Phase::Serial(run) if worker == 0 => {
+                        for &node_id in run {
+                            propagate(map, nodes, gradients, node_id);
+                        }
+                    }
+                    Phase::Serial(_) => {}
+                    Phase::Parallel(level) => {
+                        let chunk_size = level.len().div_ceil(threads);
+                        if let Some(chunk) = level.chunks(chunk_size).nth(worker) {
+                            for &node_id in chunk {
+                                propagate(map, nodes, gradients, node_id);
+                            }
+                        }
+                    }
+                }
+                barrier.wait();
+            }
+        };
+
+        let barrier = Barrier::new(threads);
+        thread::scope(|scope| {
+            for worker in 1..threads {
+                let (work, barrier) = (&work, &barrier);
+                scope.spawn(move || work(worker, barrier));
+            }
+            work(0, &barrier);
+        });
+    }
+
+    for (node, gradient) in nodes.iter_mut().zip(gradients) {
+        node.gradient = T::from_bits64(gradient.into_inner());
+    }
+}
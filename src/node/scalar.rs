@@ -0,0 +1,233 @@
+use super::Operator;
+use smallvec::{smallvec, SmallVec};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fmt;
+use std::ops::{Add, Div, Mul, Neg, Sub};
+use std::rc::Rc;
+
+struct Data {
+    value: f64,
+    gradient: f64,
+    operator: Option<Operator<f64>>,
+    children: SmallVec<[Scalar; 2]>,
+}
+
+// Dropping the last handle to a long chain would otherwise free it one
+// recursive call per node.
+impl Drop for Data {
+    fn drop(&mut self) {
+        let mut stack: Vec<Scalar> = self.children.drain(..).collect();
+        while let Some(Scalar(node)) = stack.pop() {
+            if let Some(cell) = Rc::into_inner(node) {
+                stack.extend(cell.into_inner().children.drain(..));
+            }
+        }
+    }
+}
+
+/// A reference-counted graph node in the style of micrograd's `Value`, for
+/// scripting without a node arena. The gradient rules are the same ones the
+/// arena ops use.
+///
+/// Clones share the node, so a `Scalar` can feed several expressions.
+#[derive(Clone)]
+pub struct Scalar(Rc<RefCell<Data>>);
+
+impl Scalar {
+    pub fn new(value: f64) -> Self {
+        Scalar::build(value, None, SmallVec::new())
+    }
+
+    fn build(value: f64, operator: Option<Operator<f64>>, children: SmallVec<[Scalar; 2]>) -> Self {
+        Scalar(Rc::new(RefCell::new(Data {
+            value,
+            gradient: 0.0,
+            operator,
+            children,
+        })))
+    }
+
+    fn apply(operator: Operator<f64>, children: SmallVec<[Scalar; 2]>) -> Self {
+        let inputs: SmallVec<[f64; 2]> = children.iter().map(Scalar::value).collect();
+        Scalar::build(operator.forward(&inputs), Some(operator), children)
+    }
+
+    pub fn value(&self) -> f64 {
+        self.0.borrow().value
+    }
+
+    pub fn gradient(&self) -> f64 {
+        self.0.borrow().gradient
+    }
+
+    pub fn pow(&self, exponent: &Scalar) -> Scalar {
+        Scalar::apply(Operator::Pow, smallvec![self.clone(), exponent.clone()])
+    }
+
+    pub fn max(&self, other: &Scalar) -> Scalar {
+        Scalar::apply(Operator::Max, smallvec![self.clone(), other.clone()])
+    }
+
+    pub fn min(&self, other: &Scalar) -> Scalar {
+        Scalar::apply(Operator::Min, smallvec![self.clone(), other.clone()])
+    }
+
+    pub fn relu(&self) -> Scalar {
+        Scalar::apply(Operator::Relu, smallvec![self.clone()])
+    }
+
+    pub fn tanh(&self) -> Scalar {
+        Scalar::apply(Operator::Tanh, smallvec![self.clone()])
+    }
+
+    pub fn sigmoid(&self) -> Scalar {
+        Scalar::apply(Operator::Sigmoid, smallvec![self.clone()])
+    }
+
+    pub fn leaky_relu(&self, alpha: f64) -> Scalar {
+        Scalar::apply(Operator::LeakyRelu(alpha), smallvec![self.clone()])
+    }
+
+    pub fn gelu(&self) -> Scalar {
+        Scalar::apply(Operator::Gelu, smallvec![self.clone()])
+    }
+
+    pub fn elu(&self, alpha: f64) -> Scalar {
+        Scalar::apply(Operator::Elu(alpha), smallvec![self.clone()])
+    }
+
+    pub fn mish(&self) -> Scalar {
+        Scalar::apply(Operator::Mish, smallvec![self.clone()])
+    }
+
+    pub fn sqrt(&self) -> Scalar {
+        Scalar::apply(Operator::Sqrt, smallvec![self.clone()])
+    }
+
+    pub fn abs(&self) -> Scalar {
+        Scalar::apply(Operator::Abs, smallvec![self.clone()])
+    }
+
+    pub fn clamp(&self, lo: f64, hi: f64) -> Scalar {
+        Scalar::apply(Operator::Clamp(lo, hi), smallvec![self.clone()])
+    }
+
+    pub fn sin(&self) -> Scalar {
+        Scalar::apply(Operator::Sin, smallvec![self.clone()])
+    }
+
+    pub fn cos(&self) -> Scalar {
+        Scalar::apply(Operator::Cos, smallvec![self.clone()])
+    }
+
+    pub fn exp(&self) -> Scalar {
+        Scalar::apply(Operator::Exp, smallvec![self.clone()])
+    }
+
+    pub fn ln(&self) -> Scalar {
+        Scalar::apply(Operator::Ln(None), smallvec![self.clone()])
+    }
+
+    /// Nodes reachable from `self`, every node after all of its inputs. The
+    /// search keeps its own stack, so long chains cannot overflow the thread's.
+    fn topological_order(&self) -> Vec<Scalar> {
+        let mut seen: HashSet<*const RefCell<Data>> = HashSet::new();
+        let mut order = Vec::new();
+        // `true` once the node's inputs have been pushed above it.
+        let mut stack = vec![(self.clone(), false)];
+
+        while let Some((node, expanded)) = stack.pop() {
+            if expanded {
+                order.push(node);
+            } else if seen.insert(Rc::as_ptr(&node.0)) {
+                let children = node.0.borrow().children.clone();
+                stack.push((node, true));
+                stack.extend(children.into_iter().map(|child| (child, false)));
+            }
+        }
+
+        order
+    }
+
+    /// Sets this node's gradient to 1.0 and accumulates gradients into every
+    /// node it depends on. As in `node::backwards`, leaves keep accumulating
+    /// across calls while computed nodes start over from zero.
+    pub fn backward(&self) {
+        let order = self.topological_order();
+        for node in &order {
+            let mut data = node.0.borrow_mut();
+            if data.operator.is_some() {
+                data.gradient = 0.0;
+            }
+        }
+        self.0.borrow_mut().gradient = 1.0;
+
+        for node in order.iter().rev() {
+            let data = node.0.borrow();
+            let Some(operator) = &data.operator else {
+                continue;
+            };
+            if operator.stops_gradient() {
+                continue;
+            }
+
+            let inputs: SmallVec<[f64; 2]> = data.children.iter().map(Scalar::value).collect();
+            let gradients = operator.backward(data.value, data.gradient, &inputs);
+
+            for (child, gradient) in data.children.iter().zip(gradients) {
+                child.0.borrow_mut().gradient += gradient;
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Scalar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Scalar")
+            .field("value", &self.value())
+            .field("gradient", &self.gradient())
+            .finish()
+    }
+}
+
+impl Neg for Scalar {
+    type Output = Scalar;
+
+    fn neg(self) -> Scalar {
+        Scalar::apply(Operator::Neg, smallvec![self])
+    }
+}
+
+impl Neg for &Scalar {
+    type Output = Scalar;
+
+    fn neg(self) -> Scalar {
+        Scalar::apply(Operator::Neg, smallvec![self.clone()])
+    }
+}
+
+macro_rules! binary_operator {
+    ($trait:ident, $method:ident, $operator:expr) => {
+        impl $trait for Scalar {
+            type Output = Scalar;
+
+            fn $method(self, other: Scalar) -> Scalar {
+                Scalar::apply($operator, smallvec![self, other])
+            }
+        }
+
+        impl $trait for &Scalar {
+            type Output = Scalar;
+
+            fn $method(self, other: &Scalar) -> Scalar {
+                Scalar::apply($operator, smallvec![self.clone(), other.clone()])
+            }
+        }
+    };
+}
+
+binary_operator!(Add, add, Operator::Plus);
+binary_operator!(Sub, sub, Operator::Sub);
+binary_operator!(Mul, mul, Operator::Mul);
+binary_operator!(Div, div, Operator::Div);
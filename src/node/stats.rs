@@ -0,0 +1,65 @@
+use super::{Map, Node};
+use crate::float::Float;
+use std::collections::BTreeMap;
+use std::mem;
+
+#[derive(Debug, Clone)]
+pub struct GraphStats {
+    pub node_count: usize,
+    pub leaf_count: usize,
+    /// Number of input links recorded in the parent map; a node that uses
+    /// the same input twice counts it twice.
+    pub edge_count: usize,
+    /// Number of nodes built by each operator, keyed by operator name.
+    pub operator_counts: BTreeMap<&'static str, usize>,
+    /// Length of the longest path from a leaf to any node.
+    pub depth: usize,
+    /// Maps a fan-out (how many nodes consume a node) to how many nodes have it.
+    pub fan_out_histogram: BTreeMap<usize, usize>,
+    /// Rough heap usage of the node arena and parent map.
+    pub memory_bytes: usize,
+}
+
+pub fn stats<T: Float>(map: &Map, nodes: &[Node<T>]) -> GraphStats {
+    let mut operator_counts = BTreeMap::new();
+    let mut depths = vec![0; nodes.len()];
+    let mut fan_out = vec![0; nodes.len()];
+
+    for (node_id, node) in nodes.iter().enumerate() {
+        if let Some(operator) = &node.operator {
+            *operator_counts.entry(operator.name()).or_insert(0) += 1;
+        }
+
+        if let Some(children) = map.get(&node_id) {
+            for &child in children {
+                fan_out[child] += 1;
+                depths[node_id] = depths[node_id].max(depths[child] + 1);
+            }
+        }
+    }
+
+    let mut fan_out_histogram = BTreeMap::new();
+    for count in fan_out {
+        *fan_out_histogram.entry(count).or_insert(0) += 1;
+    }
+
+    // A hash map entry roughly costs its key, its value and a control byte;
+    // nodes with more than two inputs keep them in a separate allocation.
+    let entry_size = mem::size_of::<usize>() + mem::size_of::<super::Children>() + 1;
+    let spilled: usize = map
+        .values()
+        .filter(|children| children.spilled())
+        .map(|children| children.capacity() * mem::size_of::<usize>())
+        .sum();
+    let memory_bytes = mem::size_of_val(nodes) + map.capacity() * entry_size + spilled;
+
+    GraphStats {
+        node_count: nodes.len(),
+        leaf_count: nodes.iter().filter(|n| n.operator.is_none()).count(),
+        edge_count: map.values().map(|children| children.len()).sum(),
+        operator_counts,
+        depth: depths.into_iter().max().unwrap_or(0),
+        fan_out_histogram,
+        memory_bytes,
+    }
+}
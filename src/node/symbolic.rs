@@ -0,0 +1,375 @@
+use super::{
+    abs, add, add_scalar, backwards, constant, cos, div, dot, exp, ge, gt, le, ln, max, mul,
+    mul_scalar, neg, pow, prod, relu, select, sigmoid, sign, sin, sub, tanh, truncate, zero_grad,
+    Map, Node, Operator, Ste, GELU_CUBIC, GELU_SCALE,
+};
+use crate::error::GraphError;
+use crate::float::Float;
+use std::collections::HashMap;
+
+/// Builds the gradient of `output` with respect to each of `wrt` as new nodes
+/// of the graph, instead of writing it into `Node::gradient` as `backwards`
+/// does. The results are ordinary nodes, so they can take part in a loss
+/// (e.g. a gradient penalty) and be differentiated again.
+///
+/// An input that `output` does not depend on gets a constant zero. Every
+/// built-in operator has a rule; a custom op that a gradient has to pass
+/// through gives `GraphError::Unsupported`, and the graph is left as it was.
+pub fn grad<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    output: usize,
+    wrt: &[usize],
+) -> Result<Vec<usize>, GraphError> {
+    let len = nodes.len();
+    let mut adjoints: HashMap<usize, usize> = HashMap::new();
+    adjoints.insert(output, constant(nodes, T::one()));
+
+    // Same order as `backpropagate`: every adjoint is complete before it is
+    // passed on. New nodes get ids above `output`, so they are never visited.
+    for node_id in (0..=output).rev() {
+        let Some(&adjoint) = adjoints.get(&node_id) else {
+            continue;
+        };
+        let (Some(operator), Some(children)) =
+            (nodes[node_id].operator.clone(), map.get(&node_id).cloned())
+        else {
+            continue;
+        };
+        if operator.stops_gradient() {
+            continue;
+        }
+
+        let needs: Vec<bool> = children.iter().map(|&c| nodes[c].requires_grad).collect();
+        if !needs.contains(&true) {
+            continue;
+        }
+        let Some(contributions) = rule(map, nodes, &operator, node_id, &children, adjoint, &needs)
+        else {
+            truncate(map, nodes, len);
+            return Err(GraphError::Unsupported(node_id));
+        };
+
+        for ((&child, contribution), needed) in children.iter().zip(contributions).zip(needs) {
+            if !needed {
+                continue;
+            }
+            let total = match adjoints.get(&child) {
+                Some(&previous) => add(map, nodes, previous, contribution).0,
+                None => contribution,
+            };
+            adjoints.insert(child, total);
+        }
+    }
+
+    Ok(wrt
+        .iter()
+        .map(|id| match adjoints.get(id) {
+            Some(&adjoint) => adjoint,
+            None => constant(nodes, T::zero()),
+        })
+        .collect())
+}
+
+/// Hessian-vector product `H v` of `output` with respect to `params`, by
+/// double backward: the gradient is built with `grad`, dotted with `v`, and
+/// that scalar is backpropagated. The nodes this adds are removed again and
+/// gradients are restored, so the graph is left as it was. Fails as `grad`
+/// does.
+pub fn hvp<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    output: usize,
+    params: &[usize],
+    v: &[T],
+) -> Result<Vec<T>, GraphError> {
+    assert_eq!(params.len(), v.len());
+
+    let len = nodes.len();
+    let saved: Vec<T> = nodes.iter().map(|n| n.gradient).collect();
+
+    let gradients = grad(map, nodes, output, params)?;
+    let directions: Vec<usize> = v.iter().map(|&x| constant(nodes, x)).collect();
+    let projection = dot(map, nodes, &gradients, &directions);
+
+    zero_grad(nodes);
+    backwards(map, nodes, projection);
+    let product = params.iter().map(|&p| nodes[p].gradient).collect();
+
+    truncate(map, nodes, len);
+    for (node, gradient) in nodes.iter_mut().zip(saved) {
+        node.gradient = gradient;
+    }
+
+    Ok(product)
+}
+
+// The gradient contribution to each child of `node_id` as nodes, mirroring
+// `Operator::backward`, or `None` for custom ops. The exponent of `pow` is
+// skipped unless it needs a gradient, since `ln` of its base is often
+// undefined; `adjoint` stands in.
+fn rule<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    operator: &Operator<T>,
+    node_id: usize,
+    children: &[usize],
+    adjoint: usize,
+    needs: &[bool],
+) -> Option<Vec<usize>> {
+    let a = children[0];
+    let g = adjoint;
+
+    let contributions = match operator {
+        Operator::Plus | Operator::Identity | Operator::Sum => vec![g; children.len()],
+        Operator::Sub => vec![g, neg(map, nodes, g).0],
+        Operator::Neg => vec![neg(map, nodes, g).0],
+        Operator::Mean => {
+            let share = T::one() / T::from_f64(children.len() as f64);
+            vec![mul_scalar(map, nodes, g, share).0; children.len()]
+        }
+        Operator::Mul => {
+            let b = children[1];
+            vec![mul(map, nodes, g, b).0, mul(map, nodes, g, a).0]
+        }
+        Operator::Div => {
+            // d/db (a / b) = -(a / b) / b
+            let b = children[1];
+            let (scaled, _) = mul(map, nodes, g, node_id);
+            let (quotient, _) = div(map, nodes, scaled, b);
+            vec![div(map, nodes, g, b).0, neg(map, nodes, quotient).0]
+        }
+        Operator::Pow => {
+            let b = children[1];
+            let (lowered, _) = add_scalar(map, nodes, b, -T::one());
+            let (power, _) = pow(map, nodes, a, lowered);
+            let (derivative, _) = mul(map, nodes, b, power);
+            let base = mul(map, nodes, g, derivative).0;
+
+            let exponent = if needs[1] {
+                let (ln_a, _) = ln(map, nodes, a);
+                let (derivative, _) = mul(map, nodes, node_id, ln_a);
+                mul(map, nodes, g, derivative).0
+            } else {
+                g
+            };
+            vec![base, exponent]
+        }
+        Operator::Prod => (0..children.len())
+            .map(|i| {
+                let others: Vec<usize> = children
+                    .iter()
+                    .enumerate()
+                    .filter(|&(j, _)| j != i)
+                    .map(|(_, &c)| c)
+                    .collect();
+                if others.is_empty() {
+                    g
+                } else {
+                    let product = prod(map, nodes, &others);
+                    mul(map, nodes, g, product).0
+                }
+            })
+            .collect(),
+        Operator::Exp => vec![mul(map, nodes, g, node_id).0],
+        Operator::Ln(None) => vec![div(map, nodes, g, a).0],
+        Operator::Ln(Some(eps)) => {
+            // Dividing by `max(a, eps)` keeps the masked-out side finite.
+            let eps = constant(nodes, *eps);
+            let (floor, _) = max(map, nodes, a, eps);
+            let (mask, _) = gt(map, nodes, a, eps);
+            let (scaled, _) = div(map, nodes, g, floor);
+            vec![mul(map, nodes, scaled, mask).0]
+        }
+        Operator::Sqrt => {
+            let (double, _) = mul_scalar(map, nodes, node_id, T::from_f64(2.0));
+            vec![div(map, nodes, g, double).0]
+        }
+        Operator::Sin => {
+            let (derivative, _) = cos(map, nodes, a);
+            vec![mul(map, nodes, g, derivative).0]
+        }
+        Operator::Cos => {
+            let (sine, _) = sin(map, nodes, a);
+            let (derivative, _) = neg(map, nodes, sine);
+            vec![mul(map, nodes, g, derivative).0]
+        }
+        Operator::Tanh => {
+            let derivative = one_minus_square(map, nodes, node_id);
+            vec![mul(map, nodes, g, derivative).0]
+        }
+        Operator::Sigmoid => {
+            // y * (1 - y)
+            let one = constant(nodes, T::one());
+            let (complement, _) = sub(map, nodes, one, node_id);
+            let (derivative, _) = mul(map, nodes, node_id, complement);
+            vec![mul(map, nodes, g, derivative).0]
+        }
+        Operator::Relu => {
+            let zero = constant(nodes, T::zero());
+            let (mask, _) = gt(map, nodes, a, zero);
+            vec![mul(map, nodes, g, mask).0]
+        }
+        Operator::LeakyRelu(alpha) => {
+            let one = constant(nodes, T::one());
+            let alpha = constant(nodes, *alpha);
+            let (slope, _) = select(map, nodes, a, one, alpha);
+            vec![mul(map, nodes, g, slope).0]
+        }
+        Operator::Elu(alpha) => {
+            // 1 above zero, y + alpha below.
+            let one = constant(nodes, T::one());
+            let (shifted, _) = add_scalar(map, nodes, node_id, *alpha);
+            let (slope, _) = select(map, nodes, a, one, shifted);
+            vec![mul(map, nodes, g, slope).0]
+        }
+        Operator::Prelu => {
+            let slope = children[1];
+            let one = constant(nodes, T::one());
+            let zero = constant(nodes, T::zero());
+            let (da, _) = select(map, nodes, a, one, slope);
+            let (ds, _) = select(map, nodes, a, zero, a);
+            vec![mul(map, nodes, g, da).0, mul(map, nodes, g, ds).0]
+        }
+        Operator::Gelu => {
+            // 0.5 (1 + t) + 0.5 x (1 - t^2) u', with t = tanh(u) and
+            // u = s (x + c x^3).
+            let scale = T::from_f64(GELU_SCALE);
+            let cubic = T::from_f64(GELU_CUBIC);
+            let half = T::from_f64(0.5);
+
+            let (square, _) = mul(map, nodes, a, a);
+            let (cube, _) = mul(map, nodes, square, a);
+            let (scaled_cube, _) = mul_scalar(map, nodes, cube, cubic);
+            let (sum, _) = add(map, nodes, a, scaled_cube);
+            let (inner, _) = mul_scalar(map, nodes, sum, scale);
+            let (t, _) = tanh(map, nodes, inner);
+
+            let (scaled_square, _) = mul_scalar(map, nodes, square, T::from_f64(3.0) * cubic);
+            let (shifted, _) = add_scalar(map, nodes, scaled_square, T::one());
+            let (du, _) = mul_scalar(map, nodes, shifted, scale);
+
+            let (one_plus_t, _) = add_scalar(map, nodes, t, T::one());
+            let (left, _) = mul_scalar(map, nodes, one_plus_t, half);
+            let sech = one_minus_square(map, nodes, t);
+            let (half_a, _) = mul_scalar(map, nodes, a, half);
+            let (slope, _) = mul(map, nodes, sech, du);
+            let (right, _) = mul(map, nodes, half_a, slope);
+            let (derivative, _) = add(map, nodes, left, right);
+            vec![mul(map, nodes, g, derivative).0]
+        }
+        Operator::Mish => {
+            // t + x (1 - t^2) sigmoid(x), with t = tanh(softplus(x)) and
+            // softplus(x) = relu(x) + ln(1 + exp(-|x|)).
+            let (magnitude, _) = abs(map, nodes, a);
+            let (negated, _) = neg(map, nodes, magnitude);
+            let (decay, _) = exp(map, nodes, negated);
+            let (shifted, _) = add_scalar(map, nodes, decay, T::one());
+            let (log, _) = ln(map, nodes, shifted);
+            let (positive, _) = relu(map, nodes, a);
+            let (softplus, _) = add(map, nodes, positive, log);
+            let (t, _) = tanh(map, nodes, softplus);
+
+            let sech = one_minus_square(map, nodes, t);
+            let (gate, _) = sigmoid(map, nodes, a);
+            let (scaled, _) = mul(map, nodes, a, sech);
+            let (right, _) = mul(map, nodes, scaled, gate);
+            let (derivative, _) = add(map, nodes, t, right);
+            vec![mul(map, nodes, g, derivative).0]
+        }
+        Operator::Abs => {
+            let (derivative, _) = sign(map, nodes, a, Ste::None);
+            vec![mul(map, nodes, g, derivative).0]
+        }
+        Operator::Clamp(lo, hi) => {
+            let lo = constant(nodes, *lo);
+            let hi = constant(nodes, *hi);
+            let (above, _) = ge(map, nodes, a, lo);
+            let (below, _) = le(map, nodes, a, hi);
+            let (mask, _) = mul(map, nodes, above, below);
+            vec![mul(map, nodes, g, mask).0]
+        }
+        Operator::Max | Operator::Min => {
+            // Ties go to the first operand, as in `Operator::backward`.
+            let b = children[1];
+            let (mask, _) = match operator {
+                Operator::Max => ge(map, nodes, a, b),
+                _ => le(map, nodes, a, b),
+            };
+            let one = constant(nodes, T::one());
+            let (complement, _) = sub(map, nodes, one, mask);
+            vec![mul(map, nodes, g, mask).0, mul(map, nodes, g, complement).0]
+        }
+        Operator::Select => {
+            let zero = constant(nodes, T::zero());
+            let (then, _) = select(map, nodes, a, g, zero);
+            let (otherwise, _) = select(map, nodes, a, zero, g);
+            vec![zero, then, otherwise]
+        }
+        Operator::SquaredError(target) => {
+            let (error, _) = add_scalar(map, nodes, a, -*target);
+            let (derivative, _) = mul_scalar(map, nodes, error, T::from_f64(2.0));
+            vec![mul(map, nodes, g, derivative).0]
+        }
+        Operator::GaussianKl => {
+            // 0.5 (exp(log_var) - 1) for the log-variance.
+            let (variance, _) = exp(map, nodes, children[1]);
+            let (excess, _) = add_scalar(map, nodes, variance, -T::one());
+            let (derivative, _) = mul_scalar(map, nodes, excess, T::from_f64(0.5));
+            vec![mul(map, nodes, g, a).0, mul(map, nodes, g, derivative).0]
+        }
+        Operator::LogSumExp => children
+            .iter()
+            .map(|&x| {
+                // The softmax weight exp(x - y) of each input.
+                let (difference, _) = sub(map, nodes, x, node_id);
+                let (weight, _) = exp(map, nodes, difference);
+                mul(map, nodes, g, weight).0
+            })
+            .collect(),
+        Operator::Sign(ste) | Operator::Round(ste) => {
+            vec![estimator(map, nodes, *ste, a, g)]
+        }
+        Operator::Threshold(threshold, ste) => {
+            let x = match ste {
+                Ste::Clipped => add_scalar(map, nodes, a, -*threshold).0,
+                _ => a,
+            };
+            vec![estimator(map, nodes, *ste, x, g)]
+        }
+        Operator::Gt | Operator::Lt | Operator::Ge | Operator::Le | Operator::Eq => {
+            vec![constant(nodes, T::zero()); children.len()]
+        }
+        Operator::Custom(_) => return None,
+    };
+
+    Some(contributions)
+}
+
+// `1 - y^2`, the derivative of tanh in terms of its output.
+fn one_minus_square<T: Float>(map: &mut Map, nodes: &mut Vec<Node<T>>, y: usize) -> usize {
+    let (square, _) = mul(map, nodes, y, y);
+    let one = constant(nodes, T::one());
+    sub(map, nodes, one, square).0
+}
+
+// The gradient a straight-through estimator passes on from `g`, given the
+// (shifted) input `x` it is tested against.
+fn estimator<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    ste: Ste,
+    x: usize,
+    g: usize,
+) -> usize {
+    match ste {
+        Ste::None => constant(nodes, T::zero()),
+        Ste::Identity => g,
+        Ste::Clipped => {
+            let (magnitude, _) = abs(map, nodes, x);
+            let one = constant(nodes, T::one());
+            let (mask, _) = le(map, nodes, magnitude, one);
+            mul(map, nodes, g, mask).0
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use super::{Map, Node};
+use crate::float::Float;
+
+// Elementwise versions of the node operations over slices of node ids. Each
+// function appends one node per element and returns the new ids in order.
+
+type Binary<T> = fn(&mut Map, &mut Vec<Node<T>>, usize, usize) -> (usize, T);
+type Unary<T> = fn(&mut Map, &mut Vec<Node<T>>, usize) -> (usize, T);
+
+fn zip_with<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    xs: &[usize],
+    ys: &[usize],
+    op: Binary<T>,
+) -> Vec<usize> {
+    assert_eq!(xs.len(), ys.len());
+
+    xs.iter()
+        .zip(ys)
+        .map(|(&x, &y)| op(map, nodes, x, y).0)
+        .collect()
+}
+
+fn each<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    xs: &[usize],
+    op: Unary<T>,
+) -> Vec<usize> {
+    xs.iter().map(|&x| op(map, nodes, x).0).collect()
+}
+
+pub fn add<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    xs: &[usize],
+    ys: &[usize],
+) -> Vec<usize> {
+    zip_with(map, nodes, xs, ys, super::add)
+}
+
+pub fn sub<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    xs: &[usize],
+    ys: &[usize],
+) -> Vec<usize> {
+    zip_with(map, nodes, xs, ys, super::sub)
+}
+
+pub fn mul<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    xs: &[usize],
+    ys: &[usize],
+) -> Vec<usize> {
+    zip_with(map, nodes, xs, ys, super::mul)
+}
+
+pub fn div<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    xs: &[usize],
+    ys: &[usize],
+) -> Vec<usize> {
+    zip_with(map, nodes, xs, ys, super::div)
+}
+
+/// Multiplies every element by the constant `factor`.
+pub fn scale<T: Float>(
+    map: &mut Map,
+    nodes: &mut Vec<Node<T>>,
+    xs: &[usize],
+    factor: T,
+) -> Vec<usize> {
+    xs.iter()
+        .map(|&x| super::mul_scalar(map, nodes, x, factor).0)
+        .collect()
+}
+
+pub fn neg<T: Float>(map: &mut Map, nodes: &mut Vec<Node<T>>, xs: &[usize]) -> Vec<usize> {
+    each(map, nodes, xs, super::neg)
+}
+
+pub fn relu<T: Float>(map: &mut Map, nodes: &mut Vec<Node<T>>, xs: &[usize]) -> Vec<usize> {
+    each(map, nodes, xs, super::relu)
+}
+
+pub fn tanh<T: Float>(map: &mut Map, nodes: &mut Vec<Node<T>>, xs: &[usize]) -> Vec<usize> {
+    each(map, nodes, xs, super::tanh)
+}
+
+pub fn sigmoid<T: Float>(map: &mut Map, nodes: &mut Vec<Node<T>>, xs: &[usize]) -> Vec<usize> {
+    each(map, nodes, xs, super::sigmoid)
+}
+
+pub fn exp<T: Float>(map: &mut Map, nodes: &mut Vec<Node<T>>, xs: &[usize]) -> Vec<usize> {
+    each(map, nodes, xs, super::exp)
+}
@@ -0,0 +1,240 @@
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+const MAGIC: &[u8] = b"\x93NUMPY";
+
+pub struct Array {
+    pub shape: Vec<usize>,
+    pub data: Vec<f64>,
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+// Pulls the value of `key` out of the python dict literal in the header, e.g.
+// `{'descr': '<f8', 'fortran_order': False, 'shape': (3, 2), }`.
+fn header_field<'a>(header: &'a str, key: &str) -> Result<&'a str> {
+    let pattern = format!("'{}':", key);
+    let start = header
+        .find(&pattern)
+        .ok_or_else(|| invalid(format!("npy header is missing '{}'", key)))?
+        + pattern.len();
+    let rest = header[start..].trim_start();
+
+    let end = if rest.starts_with('(') {
+        rest.find(')').map(|i| i + 1)
+    } else {
+        rest.find(',')
+    };
+
+    Ok(rest[..end.unwrap_or(rest.len())].trim())
+}
+
+fn parse_shape(field: &str) -> Result<Vec<usize>> {
+    field
+        .trim_start_matches('(')
+        .trim_end_matches(')')
+        .split(',')
+        .map(|dim| dim.trim())
+        .filter(|dim| !dim.is_empty())
+        .map(|dim| {
+            dim.parse::<usize>()
+                .map_err(|_| invalid(format!("bad npy shape entry '{}'", dim)))
+        })
+        .collect()
+}
+
+pub fn parse(bytes: &[u8]) -> Result<Array> {
+    if bytes.len() < 10 || &bytes[..6] != MAGIC {
+        return Err(invalid("not an npy file".to_string()));
+    }
+
+    let major = bytes[6];
+    let (header_len, header_start) = match major {
+        1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+        2 | 3 if bytes.len() >= 12 => (
+            u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize,
+            12,
+        ),
+        _ => return Err(invalid(format!("unsupported npy version {}", major))),
+    };
+
+    let data_start = header_start + header_len;
+    if bytes.len() < data_start {
+        return Err(invalid("truncated npy header".to_string()));
+    }
+    let header = std::str::from_utf8(&bytes[header_start..data_start])
+        .map_err(|_| invalid("npy header is not utf-8".to_string()))?;
+
+    if header_field(header, "fortran_order")? != "False" {
+        return Err(invalid(
+            "fortran-ordered arrays are not supported".to_string(),
+        ));
+    }
+
+    let shape = parse_shape(header_field(header, "shape")?)?;
+    let count: usize = shape.iter().product();
+    let payload = &bytes[data_start..];
+
+    let descr = header_field(header, "descr")?.trim_matches('\'');
+    let data: Vec<f64> = match descr {
+        "<f8" => payload
+            .chunks_exact(8)
+            .take(count)
+            .map(|c| f64::from_le_bytes(c.try_into().unwrap()))
+            .collect(),
+        "<f4" => payload
+            .chunks_exact(4)
+            .take(count)
+            .map(|c| f32::from_le_bytes(c.try_into().unwrap()) as f64)
+            .collect(),
+        _ => return Err(invalid(format!("unsupported npy dtype '{}'", descr))),
+    };
+
+    if data.len() != count {
+        return Err(invalid("truncated npy data".to_string()));
+    }
+
+    Ok(Array { shape, data })
+}
+
+pub fn read<P: AsRef<Path>>(path: P) -> Result<Array> {
+    parse(&fs::read(path)?)
+}
+
+const END_OF_DIRECTORY: u32 = 0x0605_4b50;
+const ZIP64_END_LOCATOR: u32 = 0x0706_4b50;
+const ZIP64_END_OF_DIRECTORY: u32 = 0x0606_4b50;
+const DIRECTORY_ENTRY: u32 = 0x0201_4b50;
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+
+// Marks a 32-bit field whose real value is in the zip64 extra field.
+const ZIP64_MARKER: u32 = u32::MAX;
+
+fn slice(bytes: &[u8], at: usize, len: usize) -> Result<&[u8]> {
+    at.checked_add(len)
+        .and_then(|end| bytes.get(at..end))
+        .ok_or_else(|| invalid("truncated npz archive".to_string()))
+}
+
+fn field<const N: usize>(bytes: &[u8], at: usize) -> Result<[u8; N]> {
+    Ok(slice(bytes, at, N)?.try_into().unwrap())
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> Result<usize> {
+    Ok(u16::from_le_bytes(field(bytes, at)?) as usize)
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> Result<u32> {
+    Ok(u32::from_le_bytes(field(bytes, at)?))
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> Result<usize> {
+    Ok(u64::from_le_bytes(field(bytes, at)?) as usize)
+}
+
+// The entry count and offset of the central directory, from the end record,
+// or from the zip64 end record when the archive is too large for the first.
+fn directory(bytes: &[u8]) -> Result<(usize, usize)> {
+    // The end record is 22 bytes, followed by a comment of at most 64 KiB.
+    let last = bytes
+        .len()
+        .checked_sub(22)
+        .ok_or_else(|| invalid("not an npz archive".to_string()))?;
+    let end = (last.saturating_sub(u16::MAX as usize)..=last)
+        .rev()
+        .find(|&at| u32_at(bytes, at).ok() == Some(END_OF_DIRECTORY))
+        .ok_or_else(|| invalid("not an npz archive".to_string()))?;
+
+    let count = u16_at(bytes, end + 10)?;
+    let offset = u32_at(bytes, end + 16)?;
+    if count != u16::MAX as usize && offset != ZIP64_MARKER {
+        return Ok((count, offset as usize));
+    }
+
+    let locator = end
+        .checked_sub(20)
+        .filter(|&at| u32_at(bytes, at).ok() == Some(ZIP64_END_LOCATOR))
+        .ok_or_else(|| invalid("npz archive is missing its zip64 end record".to_string()))?;
+    let end64 = u64_at(bytes, locator + 8)?;
+    if u32_at(bytes, end64)? != ZIP64_END_OF_DIRECTORY {
+        return Err(invalid("bad zip64 end record in npz archive".to_string()));
+    }
+    Ok((u64_at(bytes, end64 + 32)?, u64_at(bytes, end64 + 48)?))
+}
+
+/// Reads an archive written by `numpy.savez`, returning its arrays in the
+/// order they were saved, each named without the `.npy` suffix. Archives from
+/// `numpy.savez_compressed` are rejected: only stored entries can be read.
+pub fn parse_npz(bytes: &[u8]) -> Result<Vec<(String, Array)>> {
+    let (count, mut at) = directory(bytes)?;
+    let mut arrays = Vec::with_capacity(count);
+
+    for _ in 0..count {
+        if u32_at(bytes, at)? != DIRECTORY_ENTRY {
+            return Err(invalid("bad central directory in npz archive".to_string()));
+        }
+        let method = u16_at(bytes, at + 10)?;
+        let compressed = u32_at(bytes, at + 20)?;
+        let uncompressed = u32_at(bytes, at + 24)?;
+        let name_len = u16_at(bytes, at + 28)?;
+        let extra_len = u16_at(bytes, at + 30)?;
+        let comment_len = u16_at(bytes, at + 32)?;
+        let offset = u32_at(bytes, at + 42)?;
+
+        let name = slice(bytes, at + 46, name_len)?;
+        let name = String::from_utf8_lossy(name).into_owned();
+
+        let (mut size, mut local) = (compressed as usize, offset as usize);
+        // The zip64 extra field holds, in this order, whichever of the
+        // uncompressed size, compressed size and local header offset did not
+        // fit in 32 bits.
+        let extra = slice(bytes, at + 46 + name_len, extra_len)?;
+        let mut e = 0;
+        while e + 4 <= extra.len() {
+            let (id, len) = (u16_at(extra, e)?, u16_at(extra, e + 2)?);
+            if id == 1 {
+                let mut next = e + 4;
+                let mut wide = |narrow: u32| -> Result<usize> {
+                    if narrow != ZIP64_MARKER {
+                        return Ok(narrow as usize);
+                    }
+                    next += 8;
+                    u64_at(extra, next - 8)
+                };
+                wide(uncompressed)?;
+                size = wide(compressed)?;
+                local = wide(offset)?;
+            }
+            e += 4 + len;
+        }
+
+        if method != 0 {
+            return Err(invalid(format!(
+                "npz entry '{}' is compressed; save it with numpy.savez",
+                name
+            )));
+        }
+        if u32_at(bytes, local)? != LOCAL_HEADER {
+            return Err(invalid(format!(
+                "bad local header for npz entry '{}'",
+                name
+            )));
+        }
+        let start = local + 30 + u16_at(bytes, local + 26)? + u16_at(bytes, local + 28)?;
+        let array = parse(slice(bytes, start, size)?)
+            .map_err(|e| invalid(format!("npz entry '{}': {}", name, e)))?;
+
+        let name = name.strip_suffix(".npy").unwrap_or(&name).to_string();
+        arrays.push((name, array));
+        at += 46 + name_len + extra_len + comment_len;
+    }
+
+    Ok(arrays)
+}
+
+pub fn read_npz<P: AsRef<Path>>(path: P) -> Result<Vec<(String, Array)>> {
+    parse_npz(&fs::read(path)?)
+}
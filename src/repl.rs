@@ -0,0 +1,302 @@
+use crate::node::{self, Map, Node};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "\
+expressions use numbers, variables, + - * /, parentheses, relu(x) and exp(x)
+  name = expr   assign the value of expr to a variable
+  expr          evaluate, backpropagate, and print the gradient of each variable
+  :vars         list variables
+  :dot          print the last expression's graph in DOT format
+  :help         show this message
+  :quit         exit";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Symbol(char),
+}
+
+fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' {
+            let mut text = String::new();
+            while let Some(&d) = chars.peek() {
+                if d.is_ascii_digit() || d == '.' {
+                    text.push(d);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            // An exponent such as `e-3`, but only if digits follow, so `2e`
+            // is still an error rather than a product with a variable `e`.
+            let mut ahead = chars.clone();
+            if let Some(e @ ('e' | 'E')) = ahead.next() {
+                let sign = ahead.next_if(|&d| d == '+' || d == '-');
+                if ahead.peek().is_some_and(|d| d.is_ascii_digit()) {
+                    text.push(e);
+                    text.extend(sign);
+                    chars = ahead;
+                    while let Some(d) = chars.next_if(|d| d.is_ascii_digit()) {
+                        text.push(d);
+                    }
+                }
+            }
+            let value = text.parse().map_err(|_| format!("bad number '{}'", text))?;
+            tokens.push(Token::Number(value));
+        } else if c.is_alphabetic() || c == '_' {
+            let mut text = String::new();
+            while let Some(&d) = chars.peek() {
+                if d.is_alphanumeric() || d == '_' {
+                    text.push(d);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            tokens.push(Token::Ident(text));
+        } else if "+-*/()=".contains(c) {
+            tokens.push(Token::Symbol(c));
+            chars.next();
+        } else {
+            return Err(format!("unexpected character '{}'", c));
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Recursive-descent parser that builds graph nodes as it goes.
+struct Parser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+    variables: &'a HashMap<String, f64>,
+    leaves: BTreeMap<String, usize>,
+    map: &'a mut Map,
+    nodes: &'a mut Vec<Node>,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Symbol(c)) if c == symbol => Ok(()),
+            _ => Err(format!("expected '{}'", symbol)),
+        }
+    }
+
+    fn expression(&mut self) -> Result<usize, String> {
+        let mut left = self.term()?;
+
+        while let Some(Token::Symbol(c @ ('+' | '-'))) = self.peek().cloned() {
+            self.next();
+            let right = self.term()?;
+            left = if c == '-' {
+                node::sub(self.map, self.nodes, left, right).0
+            } else {
+                node::add(self.map, self.nodes, left, right).0
+            };
+        }
+
+        Ok(left)
+    }
+
+    fn term(&mut self) -> Result<usize, String> {
+        let mut left = self.unary()?;
+
+        while let Some(Token::Symbol(c @ ('*' | '/'))) = self.peek().cloned() {
+            self.next();
+            let right = self.unary()?;
+            left = if c == '/' {
+                node::div(self.map, self.nodes, left, right).0
+            } else {
+                node::mul(self.map, self.nodes, left, right).0
+            };
+        }
+
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<usize, String> {
+        if let Some(Token::Symbol('-')) = self.peek() {
+            self.next();
+            let x = self.unary()?;
+            return Ok(node::neg(self.map, self.nodes, x).0);
+        }
+
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<usize, String> {
+        match self.next() {
+            Some(Token::Number(value)) => Ok(node::new_node(self.nodes, value)),
+            Some(Token::Symbol('(')) => {
+                let x = self.expression()?;
+                self.expect(')')?;
+                Ok(x)
+            }
+            Some(Token::Ident(name)) if self.peek() == Some(&Token::Symbol('(')) => {
+                self.next();
+                let x = self.expression()?;
+                self.expect(')')?;
+
+                match name.as_str() {
+                    "relu" => Ok(node::relu(self.map, self.nodes, x).0),
+                    "exp" => Ok(node::exp(self.map, self.nodes, x).0),
+                    _ => Err(format!("unknown function '{}'", name)),
+                }
+            }
+            Some(Token::Ident(name)) => {
+                if let Some(&leaf) = self.leaves.get(&name) {
+                    return Ok(leaf);
+                }
+
+                let value = *self
+                    .variables
+                    .get(&name)
+                    .ok_or_else(|| format!("undefined variable '{}'", name))?;
+                let leaf = node::new_node(self.nodes, value);
+                self.leaves.insert(name, leaf);
+                Ok(leaf)
+            }
+            Some(token) => Err(format!("unexpected {:?}", token)),
+            None => Err("unexpected end of input".to_string()),
+        }
+    }
+}
+
+struct Built {
+    map: Map,
+    nodes: Vec<Node>,
+    output: usize,
+    /// The leaf created for each variable the expression mentions.
+    leaves: BTreeMap<String, usize>,
+}
+
+pub(crate) struct Session {
+    variables: HashMap<String, f64>,
+    last_graph: Option<(Map, Vec<Node>)>,
+}
+
+impl Session {
+    pub(crate) fn new() -> Self {
+        Session {
+            variables: HashMap::new(),
+            last_graph: None,
+        }
+    }
+
+    /// Parses and builds `tokens` as one expression in a fresh graph.
+    fn build(&self, tokens: &[Token]) -> Result<Built, String> {
+        let mut map: Map = HashMap::new();
+        let mut nodes: Vec<Node> = Vec::new();
+
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+            variables: &self.variables,
+            leaves: BTreeMap::new(),
+            map: &mut map,
+            nodes: &mut nodes,
+        };
+
+        let output = parser.expression()?;
+        if parser.position != tokens.len() {
+            return Err(format!("unexpected {:?}", tokens[parser.position]));
+        }
+        let leaves = parser.leaves;
+
+        Ok(Built {
+            map,
+            nodes,
+            output,
+            leaves,
+        })
+    }
+
+    /// Assigns or evaluates one line, returning what the prompt prints.
+    pub(crate) fn run(&mut self, line: &str) -> Result<String, String> {
+        let tokens = tokenize(line)?;
+
+        if let [Token::Ident(name), Token::Symbol('='), rest @ ..] = tokens.as_slice() {
+            let built = self.build(rest)?;
+            let value = built.nodes[built.output].value;
+            self.variables.insert(name.clone(), value);
+            return Ok(format!("{} = {}", name, value));
+        }
+
+        let Built {
+            mut map,
+            mut nodes,
+            output,
+            leaves,
+        } = self.build(&tokens)?;
+
+        node::backwards(&mut map, &mut nodes, output);
+
+        let mut report = format!("{}", nodes[output].value);
+        for (name, leaf) in &leaves {
+            report.push_str(&format!("\n  d/d{} = {}", name, nodes[*leaf].gradient));
+        }
+
+        self.last_graph = Some((map, nodes));
+        Ok(report)
+    }
+}
+
+/// Reads lines from stdin until EOF or `:quit`.
+pub fn run() -> io::Result<()> {
+    let mut session = Session::new();
+
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+
+    println!("rustygrad repl, :help for commands");
+
+    loop {
+        print!("> ");
+        stdout.flush()?;
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+
+        match line.trim() {
+            "" => {}
+            ":quit" | ":q" => return Ok(()),
+            ":help" => println!("{}", HELP),
+            ":vars" => {
+                let mut names: Vec<_> = session.variables.iter().collect();
+                names.sort_by(|a, b| a.0.cmp(b.0));
+                for (name, value) in names {
+                    println!("{} = {}", name, value);
+                }
+            }
+            ":dot" => match &session.last_graph {
+                Some((map, nodes)) => print!("{}", node::export::dot(map, nodes)),
+                None => println!("no expression evaluated yet"),
+            },
+            input => match session.run(input) {
+                Ok(report) => println!("{}", report),
+                Err(error) => println!("error: {}", error),
+            },
+        }
+    }
+}
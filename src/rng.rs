@@ -0,0 +1,39 @@
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
+
+/// Source of randomness for everything in the crate that needs it.
+///
+/// Any `rand` generator works as a backend; implement it directly to plug in
+/// a deterministic counter for tests or a hardware RNG.
+pub trait RngBackend {
+    /// Returns a sample from the uniform distribution on `[0, 1)`.
+    fn next_f64(&mut self) -> f64;
+
+    fn uniform(&mut self, low: f64, high: f64) -> f64 {
+        low + (high - low) * self.next_f64()
+    }
+
+    /// Returns a sample from the standard normal distribution (Box-Muller).
+    fn normal(&mut self) -> f64 {
+        let u1 = 1.0 - self.next_f64();
+        let u2 = self.next_f64();
+
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+    }
+}
+
+impl<R: RngCore> RngBackend for R {
+    fn next_f64(&mut self) -> f64 {
+        self.gen()
+    }
+}
+
+pub fn default_rng() -> StdRng {
+    StdRng::from_entropy()
+}
+
+/// A generator that produces the same sequence on every run, for reproducible
+/// weight initialization.
+pub fn seeded_rng(seed: u64) -> StdRng {
+    StdRng::seed_from_u64(seed)
+}
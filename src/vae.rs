@@ -0,0 +1,107 @@
+use crate::node::{self, Layer, Map, Node};
+use crate::rng::{self, RngBackend};
+
+/// A small variational autoencoder: a hidden encoder layer feeding separate
+/// `mu` and `log_var` heads, a reparameterized latent sample, and a decoder
+/// back to the input size.
+pub struct Vae {
+    encoder: Layer,
+    mu: Layer,
+    log_var: Layer,
+    decoder: Layer,
+}
+
+pub struct VaeOutput {
+    pub reconstruction: Vec<usize>,
+    pub mu: Vec<usize>,
+    pub log_var: Vec<usize>,
+    pub z: Vec<usize>,
+}
+
+impl Vae {
+    pub fn new(nodes: &mut Vec<Node>, count_in: u64, count_hidden: u64, count_latent: u64) -> Self {
+        Vae::new_with_rng(
+            nodes,
+            count_in,
+            count_hidden,
+            count_latent,
+            &mut rng::default_rng(),
+        )
+    }
+
+    pub fn new_with_rng(
+        nodes: &mut Vec<Node>,
+        count_in: u64,
+        count_hidden: u64,
+        count_latent: u64,
+        rng: &mut impl RngBackend,
+    ) -> Self {
+        Vae {
+            encoder: Layer::new_with_rng(nodes, count_in, count_hidden, rng),
+            mu: Layer::new_with_rng(nodes, count_hidden, count_latent, rng),
+            log_var: Layer::new_with_rng(nodes, count_hidden, count_latent, rng),
+            decoder: Layer::new_with_rng(nodes, count_latent, count_in, rng),
+        }
+    }
+
+    pub fn forward(
+        &self,
+        map: &mut Map,
+        nodes: &mut Vec<Node>,
+        x: Vec<usize>,
+        rng: &mut impl RngBackend,
+    ) -> VaeOutput {
+        let hidden = self.encoder.connect(map, nodes, x);
+        let mu = self.mu.connect(map, nodes, hidden.clone());
+        let log_var = self.log_var.connect(map, nodes, hidden);
+
+        let z: Vec<usize> = mu
+            .iter()
+            .zip(&log_var)
+            .map(|(&m, &lv)| node::sample_normal(map, nodes, m, lv, rng).0)
+            .collect();
+
+        let reconstruction = self.decoder.connect(map, nodes, z.clone());
+
+        VaeOutput {
+            reconstruction,
+            mu,
+            log_var,
+            z,
+        }
+    }
+
+    /// Negative ELBO for one sample: squared reconstruction error against
+    /// `targets` plus the KL divergence of the latent posterior from N(0, 1).
+    pub fn loss(
+        &self,
+        map: &mut Map,
+        nodes: &mut Vec<Node>,
+        output: &VaeOutput,
+        targets: &[f64],
+    ) -> usize {
+        assert!(output.reconstruction.len() == targets.len());
+
+        let kl = node::gaussian_kl(map, nodes, &output.mu, &output.log_var);
+
+        output
+            .reconstruction
+            .iter()
+            .zip(targets)
+            .fold(kl, |total, (&x_hat, &target)| {
+                let (error, _) = node::squared_error(map, nodes, x_hat, target);
+                node::add(map, nodes, total, error).0
+            })
+    }
+
+    pub fn parameters(&self) -> Vec<usize> {
+        let mut p = Vec::new();
+
+        p.extend(self.encoder.parameters());
+        p.extend(self.mu.parameters());
+        p.extend(self.log_var.parameters());
+        p.extend(self.decoder.parameters());
+
+        p
+    }
+}
@@ -0,0 +1,185 @@
+use crate::float::Float;
+use crate::graph::Graph;
+use std::cell::RefCell;
+use std::fmt;
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+/// A handle to one node of a shared `Graph`, so expressions can be written
+/// with ordinary operators: `let y = (x * w + b).relu();`.
+///
+/// Every operation appends a node to the graph, exactly as calling the
+/// corresponding `Graph` method would.
+#[derive(Clone, Copy)]
+pub struct Value<'g, T = f64> {
+    graph: &'g RefCell<Graph<T>>,
+    id: usize,
+}
+
+impl<'g, T: Float> Value<'g, T> {
+    /// A new leaf holding `value`.
+    pub fn new(graph: &'g RefCell<Graph<T>>, value: T) -> Self {
+        let id = graph.borrow_mut().leaf(value);
+        Value { graph, id }
+    }
+
+    /// A handle to an existing node.
+    pub fn from_id(graph: &'g RefCell<Graph<T>>, id: usize) -> Self {
+        assert!(id < graph.borrow().len());
+        Value { graph, id }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn data(&self) -> T {
+        self.graph.borrow().value(self.id)
+    }
+
+    pub fn grad(&self) -> T {
+        self.graph.borrow().gradient(self.id)
+    }
+
+    fn apply(self, f: impl FnOnce(&mut Graph<T>) -> usize) -> Value<'g, T> {
+        let id = f(&mut self.graph.borrow_mut());
+        Value {
+            graph: self.graph,
+            id,
+        }
+    }
+
+    fn check_same_graph(&self, other: &Value<'g, T>) {
+        assert!(
+            std::ptr::eq(self.graph, other.graph),
+            "values belong to different graphs"
+        );
+    }
+
+    pub fn pow(self, exponent: Value<'g, T>) -> Value<'g, T> {
+        self.check_same_graph(&exponent);
+        self.apply(|g| g.pow(self.id, exponent.id))
+    }
+
+    pub fn pow_scalar(self, exponent: T) -> Value<'g, T> {
+        self.apply(|g| g.pow_scalar(self.id, exponent))
+    }
+
+    pub fn max(self, other: Value<'g, T>) -> Value<'g, T> {
+        self.check_same_graph(&other);
+        self.apply(|g| g.max(self.id, other.id))
+    }
+
+    pub fn min(self, other: Value<'g, T>) -> Value<'g, T> {
+        self.check_same_graph(&other);
+        self.apply(|g| g.min(self.id, other.id))
+    }
+
+    pub fn relu(self) -> Value<'g, T> {
+        self.apply(|g| g.relu(self.id))
+    }
+
+    pub fn tanh(self) -> Value<'g, T> {
+        self.apply(|g| g.tanh(self.id))
+    }
+
+    pub fn sigmoid(self) -> Value<'g, T> {
+        self.apply(|g| g.sigmoid(self.id))
+    }
+
+    pub fn leaky_relu(self, alpha: T) -> Value<'g, T> {
+        self.apply(|g| g.leaky_relu(self.id, alpha))
+    }
+
+    pub fn gelu(self) -> Value<'g, T> {
+        self.apply(|g| g.gelu(self.id))
+    }
+
+    pub fn elu(self, alpha: T) -> Value<'g, T> {
+        self.apply(|g| g.elu(self.id, alpha))
+    }
+
+    pub fn mish(self) -> Value<'g, T> {
+        self.apply(|g| g.mish(self.id))
+    }
+
+    pub fn sqrt(self) -> Value<'g, T> {
+        self.apply(|g| g.sqrt(self.id))
+    }
+
+    pub fn abs(self) -> Value<'g, T> {
+        self.apply(|g| g.abs(self.id))
+    }
+
+    pub fn clamp(self, lo: T, hi: T) -> Value<'g, T> {
+        self.apply(|g| g.clamp(self.id, lo, hi))
+    }
+
+    pub fn sin(self) -> Value<'g, T> {
+        self.apply(|g| g.sin(self.id))
+    }
+
+    pub fn cos(self) -> Value<'g, T> {
+        self.apply(|g| g.cos(self.id))
+    }
+
+    pub fn exp(self) -> Value<'g, T> {
+        self.apply(|g| g.exp(self.id))
+    }
+
+    pub fn ln(self) -> Value<'g, T> {
+        self.apply(|g| g.ln(self.id))
+    }
+
+    /// Backpropagates from this value; see `Graph::backward`.
+    pub fn backward(&self) {
+        self.graph.borrow_mut().backward(self.id);
+    }
+}
+
+impl<T: Float> fmt::Debug for Value<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Value")
+            .field("id", &self.id)
+            .field("data", &self.data())
+            .field("grad", &self.grad())
+            .finish()
+    }
+}
+
+impl<'g, T: Float> Neg for Value<'g, T> {
+    type Output = Value<'g, T>;
+
+    fn neg(self) -> Value<'g, T> {
+        self.apply(|g| g.neg(self.id))
+    }
+}
+
+macro_rules! binary_operator {
+    ($trait:ident, $method:ident) => {
+        impl<'g, T: Float> $trait for Value<'g, T> {
+            type Output = Value<'g, T>;
+
+            fn $method(self, other: Value<'g, T>) -> Value<'g, T> {
+                self.check_same_graph(&other);
+                self.apply(|g| g.$method(self.id, other.id))
+            }
+        }
+
+        // The scalar becomes a constant leaf, so `x * 2.0` needs no `Value::new`.
+        impl<'g, T: Float> $trait<T> for Value<'g, T> {
+            type Output = Value<'g, T>;
+
+            fn $method(self, other: T) -> Value<'g, T> {
+                self.apply(|g| {
+                    let other = g.constant(other);
+                    g.$method(self.id, other)
+                })
+            }
+        }
+    };
+}
+
+binary_operator!(Add, add);
+binary_operator!(Sub, sub);
+binary_operator!(Mul, mul);
+binary_operator!(Div, div);